@@ -5,22 +5,41 @@
  */
 
 import React from "react";
+import { useState, useEffect } from "react";
+import { Error } from "react-admin";
 
 import PieChart from "./PieChart";
 
-const Allocation = ({ data, width, height, isMoney }) => {
+const Allocation = ({ portfolio, groupBy, width, height, isMoney }) => {
+  const [data, setData] = useState();
+  const [error, setError] = useState();
+
+  useEffect(() => {
+    let query = "?group_by=" + (groupBy || "symbol");
+    if (portfolio !== undefined) {
+      query += "&oid=" + portfolio;
+    }
+    fetch("http://localhost:8000/api/v1/portfolios/allocation" + query, {
+      method: "GET",
+      cache: "no-cache",
+    })
+      .then((response) => response.json())
+      .then((data) => setData(data))
+      .catch((error) => setError(error));
+  }, [portfolio, groupBy]);
+
+  if (error) return <Error />;
   if (data === undefined) return null;
 
-  const items = Object.values(data).map((item) => {
-    const total = item.costBasis + item.gain;
-    return { name: item.symbol, value: total };
+  const items = data.entries.map((item) => {
+    return { name: item.name || "Other", value: item.currentValue };
   });
 
   return (
     <React.Fragment>
       <h4>Allocation</h4>
       <div style={{ width: width, height: height }}>
-        <PieChart data={items} outerRadius={100} isMoney />
+        <PieChart data={items} outerRadius={100} isMoney={isMoney} />
       </div>
     </React.Fragment>
   );
@@ -4,11 +4,13 @@ import { Admin, Resource } from "react-admin";
 import jsonServerProvider from "ra-data-json-server";
 import {
   AttachMoney as AttachMoneyIcon,
+  Business as BusinessIcon,
   FolderSpecial as FolderSpecialIcon,
   ShoppingCart as ShoppingCartIcon,
   Store as StoreIcon,
 } from "@material-ui/icons";
 
+import { AssetCreate, AssetEdit, AssetList } from "./Assets";
 import { BrokerList, BrokerEdit, BrokerCreate } from "./Brokers";
 import { EventCreate, EventEdit, EventList } from "./Events";
 import Menu from "./Menu";
@@ -28,6 +30,14 @@ const customRoutes = [
 const dataProvider = jsonServerProvider("http://localhost:8000/api/v1");
 const App = () => (
   <Admin dataProvider={dataProvider} menu={Menu} customRoutes={customRoutes}>
+    <Resource
+      name="assets"
+      list={AssetList}
+      edit={AssetEdit}
+      create={AssetCreate}
+      icon={BusinessIcon}
+      options={{ label: "Assets" }}
+    />
     <Resource
       name="brokers"
       list={BrokerList}
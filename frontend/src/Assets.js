@@ -0,0 +1,74 @@
+import * as React from "react";
+import {
+  Create,
+  Datagrid,
+  Edit,
+  EditButton,
+  List,
+  SelectInput,
+  SimpleForm,
+  TextField,
+  TextInput,
+  required,
+} from "react-admin";
+
+const assetTypeChoices = [
+  { id: "stock", name: "Stock" },
+  { id: "fii", name: "FII" },
+  { id: "tesourodireto", name: "Tesouro Direto" },
+];
+
+export const AssetList = (props) => (
+  <List {...props}>
+    <Datagrid>
+      <TextField source="symbol" />
+      <TextField source="name" />
+      <TextField source="assetType" />
+      <TextField source="sector" />
+      <EditButton basePath="/assets" />
+    </Datagrid>
+  </List>
+);
+
+const AssetName = ({ record }) => {
+  return <span>Asset {record ? `"${record.symbol}"` : ""}</span>;
+};
+
+export const AssetEdit = (props) => (
+  <Edit title={<AssetName />} {...props}>
+    <SimpleForm>
+      <TextInput disabled source="id" />
+      <TextInput source="symbol" validate={required()} />
+      <TextInput source="name" validate={required()} />
+      <SelectInput
+        source="assetType"
+        choices={assetTypeChoices}
+        validate={required()}
+      />
+      <TextInput source="cnpj" />
+      <TextInput source="isin" />
+      <TextInput source="sector" />
+      <TextInput source="segment" />
+      <TextInput source="yahooSymbol" />
+    </SimpleForm>
+  </Edit>
+);
+
+export const AssetCreate = (props) => (
+  <Create title="Register an Asset" {...props}>
+    <SimpleForm>
+      <TextInput source="symbol" validate={required()} />
+      <TextInput source="name" validate={required()} />
+      <SelectInput
+        source="assetType"
+        choices={assetTypeChoices}
+        validate={required()}
+      />
+      <TextInput source="cnpj" />
+      <TextInput source="isin" />
+      <TextInput source="sector" />
+      <TextInput source="segment" />
+      <TextInput source="yahooSymbol" />
+    </SimpleForm>
+  </Create>
+);
@@ -5,9 +5,12 @@ import {
   Create,
   Datagrid,
   DateField,
+  DateInput,
   Edit,
   EditButton,
+  Filter,
   FormDataConsumer,
+  FunctionField,
   List,
   NumberInput,
   ReferenceArrayField,
@@ -30,19 +33,62 @@ const detailTypeChoices = [
 ];
 
 const eventTypeChoices = [
-  { id: "fii-operation", name: "FII Operation" },
   { id: "stock-operation", name: "Stock Operation" },
+  { id: "fii-operation", name: "FII Operation" },
+  { id: "tesouro-direto-operation", name: "Tesouro Direto Operation" },
   { id: "stock-split", name: "Stock Split" },
+  { id: "bonus", name: "Bonus" },
+  { id: "ticker-change", name: "Ticker Change" },
+  { id: "merger", name: "Merger" },
+  { id: "spin-off", name: "Spin-off" },
+  { id: "dividend", name: "Dividend" },
+  { id: "jcp", name: "JCP" },
+  { id: "fii-income", name: "FII Income" },
+  { id: "lending-start", name: "Lending Start" },
+  { id: "lending-end", name: "Lending End" },
+  { id: "lending-income", name: "Lending Income" },
+  { id: "custody-transfer", name: "Custody Transfer" },
 ];
 
+// Only these types have forms so far, the others are filtered on but not edited.
+const formEventTypeChoices = eventTypeChoices.filter((choice) =>
+  ["stock-operation", "fii-operation", "stock-split"].includes(choice.id)
+);
+
+const EventFilter = (props) => (
+  <Filter {...props}>
+    <TextInput label="Search" source="q" alwaysOn />
+    <TextInput source="symbol" />
+    <SelectInput source="eventType" choices={eventTypeChoices} />
+    <DateInput label="From" source="time_gte" />
+    <DateInput label="Until" source="time_lte" />
+    <ReferenceInput label="Broker" source="detail.broker" reference="brokers">
+      <SelectInput optionText="name" />
+    </ReferenceInput>
+    <ReferenceInput
+      label="Portfolio"
+      source="detail.portfolios"
+      reference="portfolios"
+    >
+      <SelectInput optionText="name" />
+    </ReferenceInput>
+  </Filter>
+);
+
+const totalFees = (record) => {
+  const fees = record.detail && record.detail.fees;
+  if (!fees || fees.total === undefined) return "";
+  return fees.total.toFixed(2);
+};
+
 export const EventList = (props) => (
-  <List {...props}>
+  <List {...props} filters={<EventFilter />}>
     <Datagrid>
       <DateField showTime source="time" />
       <TextField source="symbol" />
       <TextField source="detail.price" label="Price" />
       <TextField source="detail.quantity" label="Quantity" />
-      <TextField source="detail.fees" label="Fees" />
+      <FunctionField label="Fees" render={totalFees} />
       <TextField source="detail.type" label="Type" />
       <ReferenceField
         label="Broker"
@@ -66,6 +112,26 @@ export const EventList = (props) => (
   </List>
 );
 
+const FeesInputs = () => (
+  <Fragment>
+    <CardContentInner>
+      <NumberInput label="Corretagem" source="detail.fees.corretagem" />
+    </CardContentInner>
+    <CardContentInner>
+      <NumberInput label="Emolumentos" source="detail.fees.emolumentos" />
+    </CardContentInner>
+    <CardContentInner>
+      <NumberInput label="Liquidação" source="detail.fees.liquidacao" />
+    </CardContentInner>
+    <CardContentInner>
+      <NumberInput label="ISS" source="detail.fees.iss" />
+    </CardContentInner>
+    <CardContentInner>
+      <NumberInput label="IRRF" source="detail.fees.irrf" />
+    </CardContentInner>
+  </Fragment>
+);
+
 const StockOperationForm = (props) => (
   <Fragment>
     <CardContentInner>
@@ -78,9 +144,7 @@ const StockOperationForm = (props) => (
         validate={required()}
       />
     </CardContentInner>
-    <CardContentInner>
-      <NumberInput label="Fees" source="detail.fees" />
-    </CardContentInner>
+    <FeesInputs />
     <CardContentInner>
       <SelectInput
         label="Type"
@@ -117,9 +181,7 @@ const FIIOperationForm = (props) => (
         validate={required()}
       />
     </CardContentInner>
-    <CardContentInner>
-      <NumberInput label="Fees" source="detail.fees" />
-    </CardContentInner>
+    <FeesInputs />
     <CardContentInner>
       <SelectInput
         label="Type"
@@ -166,6 +228,9 @@ const StockSplitForm = (props) => (
         defaultValue="1"
       />
     </CardContentInner>
+    <CardContentInner>
+      <NumberInput label="Cash In" source="detail.cashIn" />
+    </CardContentInner>
   </Fragment>
 );
 
@@ -178,7 +243,7 @@ export const EventEdit = (props) => (
       <SelectInput
         label="Type"
         source="eventType"
-        choices={eventTypeChoices}
+        choices={formEventTypeChoices}
         validate={required()}
         defaultValue="stock-operation"
       />
@@ -208,7 +273,7 @@ export const EventCreate = (props) => (
       <SelectInput
         label="Type"
         source="eventType"
-        choices={eventTypeChoices}
+        choices={formEventTypeChoices}
         validate={required()}
         defaultValue="stock-operation"
       />
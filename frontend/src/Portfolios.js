@@ -5,7 +5,9 @@ import {
   Edit,
   EditButton,
   List,
+  ReferenceInput,
   ReferenceManyField,
+  SelectInput,
   Show,
   ShowButton,
   SimpleForm,
@@ -31,13 +33,13 @@ export const PortfolioShow = (props) => {
         <Divider />
         <Performance portfolio={props.id} />
         <Divider />
-        <ReferenceManyField
-          reference="portfolios/positions"
-          target="id"
-          label="Charts"
-        >
-          <Allocation height={300} width={400} isMoney={true} />
-        </ReferenceManyField>
+        <Allocation
+          portfolio={props.id}
+          groupBy="symbol"
+          height={300}
+          width={400}
+          isMoney={true}
+        />
         <ReferenceManyField
           reference="portfolios/positions"
           target="id"
@@ -69,6 +71,14 @@ export const PortfolioEdit = (props) => (
     <SimpleForm>
       <TextInput disabled source="id" />
       <TextInput source="name" />
+      <ReferenceInput
+        label="Parent"
+        source="parent"
+        reference="portfolios"
+        allowEmpty
+      >
+        <SelectInput optionText="name" />
+      </ReferenceInput>
     </SimpleForm>
   </Edit>
 );
@@ -77,6 +87,14 @@ export const PortfolioCreate = (props) => (
   <Create title="Create a Portfolio" {...props}>
     <SimpleForm>
       <TextInput source="name" />
+      <ReferenceInput
+        label="Parent"
+        source="parent"
+        reference="portfolios"
+        allowEmpty
+      >
+        <SelectInput optionText="name" />
+      </ReferenceInput>
     </SimpleForm>
   </Create>
 );
@@ -0,0 +1,201 @@
+use mongodb::bson::to_bson;
+use rocket_contrib::json::Json;
+use rocket_okapi::{openapi, JsonSchema};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::asset::Asset;
+use crate::broker::Broker;
+use crate::error::WalletResult;
+use crate::event::get_asset_kind;
+use crate::position::Position;
+use crate::walletdb::{Queryable, WalletDB};
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, JsonSchema, FromFormValue)]
+#[serde(rename_all = "lowercase")]
+pub enum AllocationGroup {
+    Kind,
+    Sector,
+    Broker,
+    Symbol,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AllocationEntry {
+    /// Name of the group, or none for holdings we know nothing about, like
+    /// symbols without a sector in the asset registry.
+    pub name: Option<String>,
+    pub current_value: f64,
+    pub percentage: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Allocation {
+    pub entries: Vec<AllocationEntry>,
+    /// Symbols still held that have no current price, and so are left out of
+    /// the entries.
+    pub unpriced: Vec<String>,
+}
+
+/// Adds up the values of each group, largest first. Values we have no price
+/// for may come as NaN, and are left out.
+fn allocate(values: Vec<(Option<String>, f64)>) -> Vec<AllocationEntry> {
+    let values = values
+        .into_iter()
+        .filter(|(_, value)| value.is_finite())
+        .collect::<Vec<(Option<String>, f64)>>();
+
+    let total = values.iter().map(|(_, value)| value).sum::<f64>();
+
+    let mut groups = HashMap::<Option<String>, f64>::new();
+    for (name, value) in values {
+        *groups.entry(name).or_insert(0.0) += value;
+    }
+
+    let mut entries = groups
+        .into_iter()
+        .filter(|(_, value)| *value != 0.0)
+        .map(|(name, current_value)| AllocationEntry {
+            name,
+            current_value,
+            percentage: if total != 0.0 {
+                100.0 * current_value / total
+            } else {
+                0.0
+            },
+        })
+        .collect::<Vec<AllocationEntry>>();
+
+    entries.sort_by(|a, b| Position::float_cmp(&b.current_value, &a.current_value));
+
+    entries
+}
+
+/// Splits each position among the brokers holding it. What no broker accounts
+/// for, like operations without a broker, is left without a name.
+fn values_by_broker(
+    positions: &[Position],
+    portfolio_oid: Option<String>,
+) -> WalletResult<Vec<(Option<String>, f64)>> {
+    let db = WalletDB::get_connection();
+    let brokers = Broker::from_docs(db.collection(Broker::collection_name()).find(None, None)?)?;
+
+    let mut remaining = positions
+        .iter()
+        .map(|position| (position.symbol.clone(), position.quantity))
+        .collect::<HashMap<String, i64>>();
+
+    // Broker-scoped positions come from their own snapshots, and only cover the
+    // symbols each broker has events for.
+    let mut values = Vec::<(Option<String>, f64)>::new();
+    for broker in brokers {
+        let broker_oid = match broker.id {
+            Some(id) => id,
+            None => continue,
+        };
+
+        for held in Position::get_all(portfolio_oid.clone(), Some(broker_oid))? {
+            if let Some(quantity) = remaining.get_mut(&held.symbol) {
+                *quantity -= held.quantity;
+                values.push((
+                    Some(broker.name.clone()),
+                    held.current_price * held.quantity as f64,
+                ));
+            }
+        }
+    }
+
+    for position in positions {
+        values.push((
+            None,
+            position.current_price * remaining[&position.symbol] as f64,
+        ));
+    }
+
+    Ok(values)
+}
+
+/// # Obtain the allocation of a portfolio
+///
+/// Returns the current market value and percentage of a portfolio, or of all
+/// portfolios if none is given, grouped by `kind`, `sector`, `broker` or `symbol`
+#[openapi]
+#[get("/portfolios/allocation?<oid>&<group_by>")]
+pub fn portfolio_allocation(
+    oid: Option<String>,
+    group_by: Option<AllocationGroup>,
+) -> WalletResult<Json<Allocation>> {
+    let positions = Position::get_all_for_portfolio(oid.clone())?;
+
+    let unpriced = positions
+        .iter()
+        .filter(|position| position.quantity != 0 && !position.current_price.is_finite())
+        .map(|position| position.symbol.clone())
+        .collect::<Vec<String>>();
+
+    let values = match group_by.unwrap_or(AllocationGroup::Symbol) {
+        AllocationGroup::Broker => values_by_broker(&positions, oid)?,
+        group_by => positions
+            .iter()
+            .map(|position| {
+                let name = match group_by {
+                    AllocationGroup::Kind => match get_asset_kind(&position.symbol)? {
+                        Some(kind) => to_bson(&kind)?.as_str().map(str::to_string),
+                        None => None,
+                    },
+                    AllocationGroup::Sector => {
+                        Asset::find(&position.symbol)?.and_then(|asset| asset.sector)
+                    }
+                    _ => Some(position.symbol.clone()),
+                };
+                Ok((name, position.current_price * position.quantity as f64))
+            })
+            .collect::<WalletResult<Vec<(Option<String>, f64)>>>()?,
+    };
+
+    Ok(Json(Allocation {
+        entries: allocate(values),
+        unpriced,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn allocation_groups() {
+        let entries = allocate(vec![
+            (Some("Financeiro".to_string()), 300.0),
+            (None, 100.0),
+            (Some("Energia".to_string()), 250.0),
+            (Some("Financeiro".to_string()), 350.0),
+        ]);
+
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].name, Some("Financeiro".to_string()));
+        assert_relative_eq!(entries[0].current_value, 650.0);
+        assert_relative_eq!(entries[0].percentage, 65.0);
+        assert_eq!(entries[1].name, Some("Energia".to_string()));
+        assert_relative_eq!(entries[1].percentage, 25.0);
+        assert_eq!(entries[2].name, None);
+        assert_relative_eq!(entries[2].percentage, 10.0);
+    }
+
+    #[test]
+    fn allocation_unpriced() {
+        let entries = allocate(vec![
+            (Some("PRCD3".to_string()), 300.0),
+            (Some("NOPR3".to_string()), std::f64::NAN),
+            (Some("PRCD4".to_string()), 100.0),
+        ]);
+
+        assert_eq!(entries.len(), 2);
+        assert_relative_eq!(entries[0].percentage, 75.0);
+        assert_relative_eq!(entries[1].percentage, 25.0);
+    }
+}
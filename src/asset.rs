@@ -0,0 +1,187 @@
+use mongodb::bson::doc;
+use rocket::request::Form;
+use rocket_contrib::json::Json;
+use rocket_okapi::{openapi, JsonSchema};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{BackendError, WalletResult};
+use crate::operation::AssetKind;
+use crate::rest::*;
+use crate::walletdb::*;
+
+/// What we know about a symbol that events can refer to.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Asset {
+    #[serde(alias = "_id")]
+    pub id: Option<String>,
+    pub symbol: String,
+    pub name: String,
+    /// CNPJ of the issuer, or of the fund for FIIs.
+    pub cnpj: Option<String>,
+    pub isin: Option<String>,
+    #[serde(rename = "assetType")]
+    pub asset_kind: AssetKind,
+    pub sector: Option<String>,
+    pub segment: Option<String>,
+    /// Symbol used for Yahoo quotes, when it is not the symbol followed by `.SA`.
+    pub yahoo_symbol: Option<String>,
+}
+
+impl Queryable for Asset {
+    fn collection_name() -> &'static str {
+        "assets"
+    }
+
+    fn search_fields() -> &'static [&'static str] {
+        &["symbol", "name", "cnpj", "isin", "sector", "segment"]
+    }
+}
+
+impl Asset {
+    pub fn find(symbol: &str) -> WalletResult<Option<Asset>> {
+        let db = WalletDB::get_connection();
+        let collection = db.collection(Asset::collection_name());
+        match collection.find_one(doc! { "symbol": symbol }, None)? {
+            Some(doc) => Ok(Some(Asset::from_doc(doc)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Each symbol can only be registered once.
+    fn check_unique(&self, oid: Option<&str>) -> WalletResult<()> {
+        match Asset::find(&self.symbol)? {
+            Some(existing) if existing.id.as_deref() != oid => Err(BackendError::Conflict(
+                format!("{} is already registered", self.symbol),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Events may only refer to registered symbols. Referring to any other is a
+    /// mistake in the request, reported against the given field.
+    pub fn check_registered(field: &str, symbol: &str) -> WalletResult<()> {
+        match Asset::find(symbol)? {
+            Some(_) => Ok(()),
+            None => Err(BackendError::validation(
+                field,
+                &format!("{} is not in the asset registry", symbol),
+            )),
+        }
+    }
+
+    pub fn yahoo_symbol(symbol: &str) -> WalletResult<String> {
+        Ok(Asset::find(symbol)?
+            .and_then(|asset| asset.yahoo_symbol)
+            .unwrap_or_else(|| format!("{}.SA", symbol)))
+    }
+}
+
+/// # Add an asset
+///
+/// Adds a new asset to the registry
+#[openapi]
+#[post("/assets", data = "<asset>")]
+pub fn add_asset(asset: Json<Asset>) -> WalletResult<Json<Asset>> {
+    asset.check_unique(None)?;
+    api_add(asset)
+}
+
+/// # List assets
+///
+/// Lists all registered assets
+#[openapi]
+#[get("/assets?<options..>")]
+pub fn get_assets(options: Option<Form<ListingOptions>>) -> WalletResult<Rest<Json<Vec<Asset>>>> {
+    api_get::<Asset>(None, options)
+}
+
+/// # Get asset
+///
+/// Get a specific asset
+#[openapi]
+#[get("/assets/<oid>")]
+pub fn get_asset_by_oid(oid: String) -> WalletResult<Json<Asset>> {
+    api_get_one::<Asset>(oid)
+}
+
+/// # Update an asset
+///
+/// Update a specific asset
+#[openapi]
+#[put("/assets/<oid>", data = "<asset>")]
+pub fn update_asset_by_oid(oid: String, asset: Json<Asset>) -> WalletResult<Json<Asset>> {
+    asset.check_unique(Some(&oid))?;
+    api_update::<Asset>(oid, asset)
+}
+
+/// # Delete an asset
+///
+/// Delete a specific asset
+#[openapi]
+#[delete("/assets/<oid>")]
+pub fn delete_asset_by_oid(oid: String) -> WalletResult<Json<Asset>> {
+    api_delete::<Asset>(oid)
+}
+
+#[cfg(test)]
+mod tests {
+    use rusty_fork::rusty_fork_test;
+
+    use super::*;
+
+    fn asset(symbol: &str) -> Asset {
+        Asset {
+            id: None,
+            symbol: symbol.to_string(),
+            name: format!("{} S.A.", symbol),
+            cnpj: None,
+            isin: None,
+            asset_kind: AssetKind::Stock,
+            sector: None,
+            segment: None,
+            yahoo_symbol: None,
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn asset_registry() {
+            WalletDB::init_client("mongodb://localhost:27017/");
+
+            let first = add_asset(Json(asset("REGI3")))
+                .expect("Failed to add asset")
+                .into_inner();
+            let second = add_asset(Json(asset("REGI4")))
+                .expect("Failed to add asset")
+                .into_inner();
+            let (first_oid, second_oid) = (first.id.clone().unwrap(), second.id.clone().unwrap());
+
+            assert_eq!(Asset::find("REGI3").unwrap(), Some(first.clone()));
+            assert_eq!(Asset::yahoo_symbol("REGI3").unwrap(), "REGI3.SA");
+
+            // A symbol can only be registered once, but an asset can be saved again.
+            match add_asset(Json(asset("REGI3"))) {
+                Err(BackendError::Conflict(_)) => (),
+                other => panic!("unexpected result {:?}", other),
+            }
+            assert!(update_asset_by_oid(first_oid.clone(), Json(first.clone())).is_ok());
+            match update_asset_by_oid(second_oid.clone(), Json(asset("REGI3"))) {
+                Err(BackendError::Conflict(_)) => (),
+                other => panic!("unexpected result {:?}", other),
+            }
+
+            assert!(Asset::check_registered("symbol", "REGI3").is_ok());
+            match Asset::check_registered("symbol", "UNREG3") {
+                Err(BackendError::Validation { field, .. }) => assert_eq!(field, "symbol"),
+                other => panic!("unexpected result {:?}", other),
+            }
+
+            delete_asset_by_oid(first_oid).expect("Failed to delete asset");
+            delete_asset_by_oid(second_oid).expect("Failed to delete asset");
+            assert_eq!(Asset::find("REGI3").unwrap(), None);
+
+            WalletDB::drop_database();
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use chrono::{Date, DateTime, Datelike, Duration, TimeZone, Utc};
+use mongodb::bson::{doc, to_bson};
+use mongodb::options::{FindOptions, UpdateOptions};
+use rocket::request::Form;
+use rocket_contrib::json::Json;
+use rocket_okapi::{openapi, JsonSchema};
+use serde::{Deserialize, Serialize};
+
+use crate::error::WalletResult;
+use crate::historical::{Historical, PriceSource};
+use crate::rest::*;
+use crate::walletdb::{Queryable, WalletDB};
+
+/// Benchmarks that follow a price, and the symbol Yahoo knows them by. Other
+/// names that are not an index are taken as Yahoo symbols themselves.
+pub const PRICE_BENCHMARKS: [(&str, &str); 1] = [("IBOV", "^BVSP")];
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub enum IndexKind {
+    CDI,
+    IPCA,
+}
+
+/// Rate of an index for the period starting at `time`, in percent: daily for
+/// CDI, monthly for IPCA.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexRate {
+    #[serde(alias = "_id")]
+    pub id: Option<String>,
+    pub index: IndexKind,
+    pub time: DateTime<Utc>,
+    pub rate: f64,
+}
+
+impl Queryable for IndexRate {
+    fn collection_name() -> &'static str {
+        "index-rates"
+    }
+}
+
+/// Builds a benchmark series for the given dates, rebased to 100 on the first, or
+/// None if there are no rates or prices for it in that period.
+pub fn benchmark_series(name: &str, dates: &[Date<Utc>]) -> WalletResult<Option<Vec<f64>>> {
+    match name {
+        "CDI" => index_series(IndexKind::CDI, dates),
+        "IPCA" => index_series(IndexKind::IPCA, dates),
+        _ => {
+            let symbol = PRICE_BENCHMARKS
+                .iter()
+                .find(|(benchmark, _)| *benchmark == name)
+                .map_or(name, |(_, symbol)| *symbol);
+            Ok(price_series(symbol, dates))
+        }
+    }
+}
+
+fn index_series(index: IndexKind, dates: &[Date<Utc>]) -> WalletResult<Option<Vec<f64>>> {
+    let (first, last) = match (dates.first(), dates.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return Ok(None),
+    };
+
+    let db = WalletDB::get_connection();
+    let collection = db.collection(IndexRate::collection_name());
+
+    // A monthly rate that started before the first date still accrues after it.
+    let filter = doc! {
+        "index": to_bson(&index)?,
+        "time": {
+            "$gte": (first.and_hms(0, 0, 0) - Duration::days(31)).to_rfc3339(),
+            "$lte": last.and_hms(23, 59, 59).to_rfc3339()
+        }
+    };
+
+    let options = FindOptions::builder().sort(doc! { "time": 1 }).build();
+    let rates = IndexRate::from_docs(collection.find(filter, options)?)?;
+    if rates.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(compound(&index, &rates, dates)))
+}
+
+/// Day after the period of a rate ends.
+fn period_end(index: &IndexKind, start: Date<Utc>) -> Date<Utc> {
+    match index {
+        IndexKind::CDI => start.succ(),
+        IndexKind::IPCA => {
+            if start.month() == 12 {
+                Utc.ymd(start.year() + 1, 1, 1)
+            } else {
+                Utc.ymd(start.year(), start.month() + 1, 1)
+            }
+        }
+    }
+}
+
+/// Compounds rates sorted by time into a series rebased to 100 on the first date.
+/// Each rate accrues day by day over its period, so a monthly rate is spread over
+/// the month instead of landing all at once on its first day.
+fn compound(index: &IndexKind, rates: &[IndexRate], dates: &[Date<Utc>]) -> Vec<f64> {
+    let mut levels = Vec::<f64>::with_capacity(dates.len());
+    let mut completed = 1.0;
+    let mut pending = 0;
+    for date in dates {
+        let day_after = date.succ();
+
+        while let Some(rate) = rates.get(pending) {
+            if period_end(index, rate.time.date()) > day_after {
+                break;
+            }
+            completed *= 1.0 + rate.rate / 100.0;
+            pending += 1;
+        }
+
+        let mut level = completed;
+        for rate in rates[pending..]
+            .iter()
+            .take_while(|rate| rate.time.date() < day_after)
+        {
+            let start = rate.time.date();
+            let elapsed = (day_after - start).num_days() as f64;
+            let length = (period_end(index, start) - start).num_days() as f64;
+            level *= (1.0 + rate.rate / 100.0).powf(elapsed / length);
+        }
+
+        levels.push(level);
+    }
+
+    let base = levels.first().cloned().unwrap_or(1.0);
+    levels
+        .into_iter()
+        .map(|level| 100.0 * level / base)
+        .collect()
+}
+
+/// Days without a price, like holidays, repeat the last known value.
+fn price_series(symbol: &str, dates: &[Date<Utc>]) -> Option<Vec<f64>> {
+    let closes = dates
+        .iter()
+        .map(|date| {
+            Historical::get_for_day_from_source(symbol, &PriceSource::Yahoo, *date)
+                .ok()
+                .map(|asset_day| asset_day.close)
+        })
+        .collect::<Vec<Option<f64>>>();
+
+    let base = closes.iter().find_map(|close| *close)?;
+
+    let mut value = 100.0;
+    Some(
+        closes
+            .into_iter()
+            .map(|close| {
+                if let Some(close) = close {
+                    value = 100.0 * close / base;
+                }
+                value
+            })
+            .collect(),
+    )
+}
+
+/// # Import index rates
+///
+/// Adds CDI or IPCA rates, used as performance benchmarks. Rates already imported
+/// for the same index and day are replaced
+#[openapi]
+#[post("/indexes/rates", data = "<rates>")]
+pub fn import_index_rates(rates: Json<Vec<IndexRate>>) -> WalletResult<()> {
+    let db = WalletDB::get_connection();
+    let collection = db.collection(IndexRate::collection_name());
+
+    for rate in rates.into_inner() {
+        let mut doc = rate.to_doc()?;
+        doc.remove("_id");
+
+        let date = rate.time.date();
+        let filter = doc! {
+            "index": to_bson(&rate.index)?,
+            "time": {
+                "$gte": date.and_hms(0, 0, 0).to_rfc3339(),
+                "$lte": date.and_hms(23, 59, 59).to_rfc3339()
+            },
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+        collection.update_one(filter, doc! { "$set": doc }, options)?;
+    }
+
+    Ok(())
+}
+
+/// # List index rates
+///
+/// Lists all imported index rates
+#[openapi]
+#[get("/indexes/rates?<options..>")]
+pub fn get_index_rates(
+    options: Option<Form<ListingOptions>>,
+) -> WalletResult<Rest<Json<Vec<IndexRate>>>> {
+    api_get::<IndexRate>(None, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    fn rate(index: IndexKind, date: Date<Utc>, rate: f64) -> IndexRate {
+        IndexRate {
+            id: None,
+            index,
+            time: date.and_hms(0, 0, 0),
+            rate,
+        }
+    }
+
+    #[test]
+    fn index_compounding() {
+        // CDI accrues in full on its day, and only after the first date.
+        let rates = vec![
+            rate(IndexKind::CDI, Utc.ymd(2020, 1, 1), 1.0),
+            rate(IndexKind::CDI, Utc.ymd(2020, 1, 2), 1.0),
+            rate(IndexKind::CDI, Utc.ymd(2020, 1, 3), 1.0),
+        ];
+        let dates = [Utc.ymd(2020, 1, 1), Utc.ymd(2020, 1, 3)];
+        let series = compound(&IndexKind::CDI, &rates, &dates);
+        assert_relative_eq!(series[0], 100.0);
+        assert_relative_eq!(series[1], 100.0 * 1.01 * 1.01);
+
+        // IPCA is spread over its month: 10 of the 31 days of January have gone
+        // by on the 10th, and all of them by the end of the month.
+        let rates = vec![
+            rate(IndexKind::IPCA, Utc.ymd(2020, 1, 1), 1.0),
+            rate(IndexKind::IPCA, Utc.ymd(2020, 2, 1), 2.0),
+        ];
+        let dates = [
+            Utc.ymd(2019, 12, 31),
+            Utc.ymd(2020, 1, 10),
+            Utc.ymd(2020, 1, 31),
+            Utc.ymd(2020, 2, 29),
+        ];
+        let series = compound(&IndexKind::IPCA, &rates, &dates);
+        assert_relative_eq!(series[0], 100.0);
+        assert_relative_eq!(series[1], 100.0 * 1.01f64.powf(10.0 / 31.0));
+        assert_relative_eq!(series[2], 101.0);
+        assert_relative_eq!(series[3], 101.0 * 1.02);
+
+        // Starting mid-month rebases on what had accrued by then.
+        let series = compound(&IndexKind::IPCA, &rates, &dates[1..3]);
+        assert_relative_eq!(series[1], 100.0 * 1.01f64.powf(21.0 / 31.0));
+
+        assert!(compound(&IndexKind::CDI, &[], &dates)
+            .iter()
+            .all(|value| *value == 100.0));
+    }
+}
@@ -4,6 +4,7 @@ use rocket_okapi::{openapi, JsonSchema};
 use serde::{Deserialize, Serialize};
 
 use crate::error::WalletResult;
+use crate::position::Position;
 use crate::rest::*;
 use crate::walletdb::*;
 
@@ -11,15 +12,19 @@ use crate::walletdb::*;
 #[serde(rename_all = "camelCase")]
 pub struct Broker {
     #[serde(alias = "_id")]
-    id: Option<String>,
-    name: String,
-    cnpj: Option<String>,
+    pub id: Option<String>,
+    pub name: String,
+    pub cnpj: Option<String>,
 }
 
 impl Queryable for Broker {
     fn collection_name() -> &'static str {
         "brokers"
     }
+
+    fn search_fields() -> &'static [&'static str] {
+        &["name", "cnpj"]
+    }
 }
 
 /// # Add a broker
@@ -66,3 +71,13 @@ pub fn update_broker_by_oid(oid: String, broker: Json<Broker>) -> WalletResult<J
 pub fn delete_broker_by_oid(oid: String) -> WalletResult<Json<Broker>> {
     api_delete::<Broker>(oid)
 }
+
+/// # List positions held at a broker
+///
+/// Lists positions considering only the operations made through a broker, for
+/// reconciling with its custody statements
+#[openapi]
+#[get("/brokers/<oid>/positions")]
+pub fn broker_positions(oid: String) -> WalletResult<Json<Vec<Position>>> {
+    Position::get_all_for_broker(oid).map(Json)
+}
@@ -0,0 +1,162 @@
+use chrono::{Date, Datelike, Duration, Utc, Weekday};
+use log::error;
+use rocket::config::ConfigError;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::Rocket;
+use rocket_okapi::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+/// How often position snapshots are taken. Daily snapshots are taken on weekdays,
+/// weekly ones on Fridays and monthly ones on the last weekday of the month.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, JsonSchema, FromFormValue)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotFrequency {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl Default for SnapshotFrequency {
+    fn default() -> Self {
+        SnapshotFrequency::Weekly
+    }
+}
+
+impl SnapshotFrequency {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "daily" => Some(SnapshotFrequency::Daily),
+            "weekly" => Some(SnapshotFrequency::Weekly),
+            "monthly" => Some(SnapshotFrequency::Monthly),
+            _ => None,
+        }
+    }
+
+    pub fn is_snapshot_date(self, date: Date<Utc>) -> bool {
+        let weekday = date.weekday();
+        if weekday == Weekday::Sat || weekday == Weekday::Sun {
+            return false;
+        }
+
+        match self {
+            SnapshotFrequency::Daily => true,
+            SnapshotFrequency::Weekly => weekday == Weekday::Fri,
+            SnapshotFrequency::Monthly => {
+                let next_weekday = if weekday == Weekday::Fri {
+                    date + Duration::days(3)
+                } else {
+                    date + Duration::days(1)
+                };
+                next_weekday.month() != date.month()
+            }
+        }
+    }
+
+    /// Identifies the period a date belongs to, so that dates can be grouped by it.
+    pub fn period(self, date: Date<Utc>) -> (i32, u32) {
+        match self {
+            SnapshotFrequency::Daily => (date.year(), date.ordinal()),
+            SnapshotFrequency::Weekly => (date.iso_week().year(), date.iso_week().week()),
+            SnapshotFrequency::Monthly => (date.year(), date.month()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Settings {
+    snapshot_frequency: SnapshotFrequency,
+    allow_short_selling: bool,
+    lot_ledger: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            snapshot_frequency: SnapshotFrequency::default(),
+            allow_short_selling: false,
+            lot_ledger: true,
+        }
+    }
+}
+
+lazy_static! {
+    static ref SETTINGS: Mutex<Settings> = Mutex::new(Settings::default());
+}
+
+/// Deployment settings read from Rocket.toml.
+pub struct WalletConfig {}
+
+impl WalletConfig {
+    pub fn fairing() -> Self {
+        WalletConfig {}
+    }
+
+    pub fn snapshot_frequency() -> SnapshotFrequency {
+        SETTINGS
+            .lock()
+            .map(|settings| settings.snapshot_frequency)
+            .expect("Failed to lock settings")
+    }
+
+    /// Whether sales may leave a negative quantity, opening a short position.
+    pub fn allow_short_selling() -> bool {
+        SETTINGS
+            .lock()
+            .map(|settings| settings.allow_short_selling)
+            .expect("Failed to lock settings")
+    }
+
+    /// Whether positions keep a ledger of the lots still held.
+    pub fn lot_ledger() -> bool {
+        SETTINGS
+            .lock()
+            .map(|settings| settings.lot_ledger)
+            .expect("Failed to lock settings")
+    }
+}
+
+impl Fairing for WalletConfig {
+    fn info(&self) -> Info {
+        Info {
+            name: "WalletConfig",
+            kind: Kind::Attach,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        let mut settings = Settings::default();
+
+        if let Ok(name) = rocket.config().get_str("snapshot_frequency") {
+            match SnapshotFrequency::from_name(name) {
+                Some(frequency) => settings.snapshot_frequency = frequency,
+                None => {
+                    error!("Invalid snapshot_frequency in Rocket.toml: {}", name);
+                    return Err(rocket);
+                }
+            }
+        }
+
+        match rocket.config().get_bool("allow_short_selling") {
+            Ok(allow) => settings.allow_short_selling = allow,
+            Err(ConfigError::Missing(_)) => (),
+            Err(e) => {
+                error!("Invalid allow_short_selling in Rocket.toml: {}", e);
+                return Err(rocket);
+            }
+        }
+
+        match rocket.config().get_bool("lot_ledger") {
+            Ok(enabled) => settings.lot_ledger = enabled,
+            Err(ConfigError::Missing(_)) => (),
+            Err(e) => {
+                error!("Invalid lot_ledger in Rocket.toml: {}", e);
+                return Err(rocket);
+            }
+        }
+
+        *SETTINGS.lock().expect("Failed to lock settings") = settings;
+
+        Ok(rocket)
+    }
+}
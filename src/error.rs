@@ -2,10 +2,11 @@ use okapi::openapi3::Responses;
 use rocket::http::Status;
 use rocket::response::Responder;
 use rocket::{Request, Response};
+use rocket_contrib::json::Json;
 use rocket_okapi::gen::OpenApiGenerator;
 use rocket_okapi::response::OpenApiResponder;
 use rocket_okapi::util::add_schema_response;
-use std::io::Cursor;
+use serde::Serialize;
 
 pub type WalletResult<T> = Result<T, BackendError>;
 
@@ -15,6 +16,26 @@ pub enum BackendError {
     Database(String),
     NotFound,
     Yahoo(String),
+    /// The request itself makes no sense, like a month that does not exist.
+    BadRequest(String),
+    /// A field of the submitted object has an invalid value.
+    Validation {
+        field: String,
+        message: String,
+    },
+    /// The submitted object clashes with one that already exists.
+    Conflict(String),
+}
+
+/// Body of every error response.
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct ErrorResponse {
+    /// Machine-readable kind of error, like `not-found` or `validation`.
+    pub code: String,
+    pub message: String,
+    /// Path of the offending field, for validation errors.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
 }
 
 #[macro_export]
@@ -48,30 +69,77 @@ impl From<std::option::NoneError> for BackendError {
     }
 }
 
-impl Responder<'static> for BackendError {
-    fn respond_to(self, _: &Request) -> Result<Response<'static>, Status> {
-        let body;
-        let status = match self {
-            BackendError::Bson(msg) => {
-                body = msg;
-                Status::new(500, "Bson")
-            }
-            BackendError::Database(msg) => {
-                body = msg;
-                Status::new(500, "Database")
-            }
+impl BackendError {
+    pub fn validation(field: &str, message: &str) -> Self {
+        BackendError::Validation {
+            field: field.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    fn status_and_body(self) -> (Status, ErrorResponse) {
+        let body = |code: &str, message: String| ErrorResponse {
+            code: code.to_string(),
+            message,
+            field: None,
+        };
+
+        match self {
+            BackendError::Bson(msg) => (Status::InternalServerError, body("bson", msg)),
+            BackendError::Database(msg) => (Status::InternalServerError, body("database", msg)),
             BackendError::NotFound => {
-                body = String::new();
-                Status::NotFound
-            }
-            BackendError::Yahoo(msg) => {
-                body = msg;
-                Status::new(500, "Yahoo")
+                (Status::NotFound, body("not-found", "Not found".to_string()))
             }
-        };
-        Response::build()
+            BackendError::Yahoo(msg) => (Status::InternalServerError, body("yahoo", msg)),
+            BackendError::BadRequest(msg) => (Status::BadRequest, body("bad-request", msg)),
+            BackendError::Validation { field, message } => (
+                Status::UnprocessableEntity,
+                ErrorResponse {
+                    code: "validation".to_string(),
+                    message,
+                    field: Some(field),
+                },
+            ),
+            BackendError::Conflict(msg) => (Status::Conflict, body("conflict", msg)),
+        }
+    }
+}
+
+/// Builds the body of errors Rocket answers on its own, like unknown routes or
+/// request bodies that do not parse.
+fn catcher_body(code: &str, message: &str) -> Json<ErrorResponse> {
+    Json(ErrorResponse {
+        code: code.to_string(),
+        message: message.to_string(),
+        field: None,
+    })
+}
+
+#[catch(400)]
+pub fn bad_request(_request: &Request) -> Json<ErrorResponse> {
+    catcher_body("bad-request", "The request could not be understood")
+}
+
+#[catch(404)]
+pub fn not_found(_request: &Request) -> Json<ErrorResponse> {
+    catcher_body("not-found", "Not found")
+}
+
+#[catch(422)]
+pub fn unprocessable_entity(_request: &Request) -> Json<ErrorResponse> {
+    catcher_body("unprocessable-entity", "The request body is not valid")
+}
+
+#[catch(500)]
+pub fn internal_error(_request: &Request) -> Json<ErrorResponse> {
+    catcher_body("internal", "Internal server error")
+}
+
+impl Responder<'static> for BackendError {
+    fn respond_to(self, request: &Request) -> Result<Response<'static>, Status> {
+        let (status, body) = self.status_and_body();
+        Response::build_from(Json(body).respond_to(request)?)
             .status(status)
-            .sized_body(Cursor::new(body))
             .ok()
     }
 }
@@ -79,9 +147,34 @@ impl Responder<'static> for BackendError {
 impl OpenApiResponder<'static> for BackendError {
     fn responses(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses> {
         let mut responses = Responses::default();
-        let schema = gen.json_schema::<String>();
-        add_schema_response(&mut responses, 500, "text/plain", schema.clone())?;
-        add_schema_response(&mut responses, 404, "text/plain", schema)?;
+        let schema = gen.json_schema::<ErrorResponse>();
+        for status in &[400, 404, 409, 422, 500] {
+            add_schema_response(&mut responses, *status, "application/json", schema.clone())?;
+        }
         Ok(responses)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_responses() {
+        let (status, body) =
+            BackendError::validation("detail.price", "Must be positive").status_and_body();
+        assert_eq!(status.code, 422);
+        assert_eq!(body.code, "validation");
+        assert_eq!(body.field, Some("detail.price".to_string()));
+
+        let (status, body) = dang!(Database, "connection refused").status_and_body();
+        assert_eq!(status.code, 500);
+        assert_eq!(body.code, "database");
+        assert_eq!(body.field, None);
+
+        let (status, _) = BackendError::BadRequest(String::new()).status_and_body();
+        assert_eq!(status.code, 400);
+        let (status, _) = BackendError::Conflict(String::new()).status_and_body();
+        assert_eq!(status.code, 409);
+    }
+}
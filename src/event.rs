@@ -1,14 +1,24 @@
 use chrono::{DateTime, Utc};
-use mongodb::bson::doc;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::FindOptions;
 use rocket::request::Form;
 use rocket_contrib::json::Json;
 use rocket_okapi::{openapi, JsonSchema};
 use serde::{Deserialize, Serialize};
 
+use crate::asset::Asset;
 use crate::error::{BackendError, WalletResult};
 use crate::fii::FIIOperation;
+use crate::income::Income;
+use crate::operation::AssetKind;
+use crate::portfolio::Portfolio;
 use crate::rest::*;
-use crate::stock::{StockOperation, StockSplit};
+use crate::stock::{
+    CustodyTransfer, Merger, SpinOff, StockBonus, StockLending, StockOperation, StockSplit,
+    TickerChange,
+};
+use crate::tesouro::TesouroDiretoOperation;
+use crate::validation::validate_event;
 use crate::walletdb::{Queryable, WalletDB};
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
@@ -29,6 +39,30 @@ impl Queryable for Event {
     fn collection_name() -> &'static str {
         "events"
     }
+
+    fn search_fields() -> &'static [&'static str] {
+        &["symbol"]
+    }
+
+    fn listing_filter(options: &ListingOptions) -> WalletResult<Document> {
+        let mut filter = options.filter(Self::search_fields())?;
+
+        if let Some(event_type) = &options.event_type {
+            filter.insert("eventType", event_type.clone());
+        }
+        if let Some(broker) = &options.broker {
+            // The free text search may already be using `$or`.
+            filter.insert(
+                "$and",
+                vec![doc! { "$or": field_matches(&BROKER_FIELDS, Bson::String(broker.clone())) }],
+            );
+        }
+        if let Some(portfolio) = &options.portfolio {
+            filter.insert("detail.portfolios", portfolios_in(portfolio)?);
+        }
+
+        Ok(filter)
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
@@ -40,16 +74,64 @@ pub enum EventDetail {
     #[serde(rename = "stock-split")]
     StockSplit(StockSplit),
 
+    #[serde(rename = "bonus")]
+    Bonus(StockBonus),
+
+    #[serde(rename = "ticker-change")]
+    TickerChange(TickerChange),
+
+    #[serde(rename = "merger")]
+    Merger(Merger),
+
+    #[serde(rename = "spin-off")]
+    SpinOff(SpinOff),
+
     #[serde(rename = "fii-operation")]
     FIIOperation(FIIOperation),
+
+    #[serde(rename = "tesouro-direto-operation")]
+    TesouroDiretoOperation(TesouroDiretoOperation),
+
+    #[serde(rename = "dividend")]
+    Dividend(Income),
+
+    #[serde(rename = "jcp")]
+    JCP(Income),
+
+    #[serde(rename = "fii-income")]
+    FIIIncome(Income),
+
+    #[serde(rename = "lending-start")]
+    LendingStart(StockLending),
+
+    #[serde(rename = "lending-end")]
+    LendingEnd(StockLending),
+
+    #[serde(rename = "lending-income")]
+    LendingIncome(Income),
+
+    #[serde(rename = "custody-transfer")]
+    CustodyTransfer(CustodyTransfer),
 }
 
+/// Event types that apply to everyone holding the symbol, regardless of the
+/// portfolios they were bought for.
+pub const CORPORATE_ACTIONS: [&str; 5] = [
+    "stock-split",
+    "bonus",
+    "ticker-change",
+    "merger",
+    "spin-off",
+];
+
 /// # Add an event
 ///
-/// Adds a new event
+/// Adds a new event, after checking that its values make sense, that its symbol,
+/// brokers and portfolios exist and that sales do not exceed the holding
 #[openapi]
 #[post("/events", data = "<event>")]
 pub fn add_event(event: Json<Event>) -> WalletResult<Json<Event>> {
+    validate_event(&event, None)?;
     api_add::<Event>(event)
 }
 
@@ -73,10 +155,11 @@ pub fn get_event_by_oid(oid: String) -> WalletResult<Json<Event>> {
 
 /// # Update an event
 ///
-/// Update a specific event
+/// Update a specific event, checking it like new ones
 #[openapi]
 #[put("/events/<oid>", data = "<event>")]
 pub fn update_event_by_oid(oid: String, event: Json<Event>) -> WalletResult<Json<Event>> {
+    validate_event(&event, Some(&oid))?;
     api_update::<Event>(oid, event)
 }
 
@@ -89,19 +172,8 @@ pub fn delete_event_by_oid(oid: String) -> WalletResult<Json<Event>> {
     api_delete::<Event>(oid)
 }
 
-pub fn get_distinct_symbols(oid: Option<String>) -> WalletResult<Vec<String>> {
-    let db = WalletDB::get_connection();
-    let collection = db.collection("events");
-
-    let filter = oid.map(|oid| {
-        doc! {
-            "detail.portfolios": &oid
-        }
-    });
-
-    let symbols = collection.distinct("symbol", filter, None)?;
-
-    symbols
+pub fn bson_to_strings(values: Vec<Bson>) -> WalletResult<Vec<String>> {
+    values
         .iter()
         .map(|s| {
             s.as_str()
@@ -110,3 +182,201 @@ pub fn get_distinct_symbols(oid: Option<String>) -> WalletResult<Vec<String>> {
         })
         .collect::<WalletResult<Vec<String>>>()
 }
+
+pub fn get_distinct_symbols(
+    portfolio_oid: Option<String>,
+    broker_oid: Option<String>,
+) -> WalletResult<Vec<String>> {
+    let db = WalletDB::get_connection();
+    let collection = db.collection("events");
+
+    let mut filter = doc! {};
+    if let Some(portfolio_oid) = portfolio_oid {
+        filter.insert("detail.portfolios", portfolios_in(&portfolio_oid)?);
+    }
+    if let Some(broker_oid) = broker_oid {
+        filter.insert(
+            "$or",
+            field_matches(&BROKER_FIELDS, Bson::String(broker_oid)),
+        );
+    }
+
+    let mut symbols = bson_to_strings(collection.distinct("symbol", Some(filter), None)?)?;
+
+    // Some symbols may only be reachable through corporate actions, like the new
+    // ticker after a rename, so keep following those until nothing new shows up.
+    let mut pending = symbols.clone();
+    while !pending.is_empty() {
+        let filter = doc! {
+            "symbol": { "$in": pending },
+            "detail.newSymbol": { "$exists": true }
+        };
+
+        pending = bson_to_strings(collection.distinct("detail.newSymbol", filter, None)?)?
+            .into_iter()
+            .filter(|s| !symbols.contains(s))
+            .collect();
+
+        symbols.extend(pending.iter().cloned());
+    }
+
+    Ok(symbols)
+}
+
+/// Finds out the kind of asset a symbol is from its operations. Symbols that only
+/// show up through corporate actions are stocks.
+pub fn get_asset_kind(symbol: &str) -> WalletResult<Option<AssetKind>> {
+    if let Some(asset) = Asset::find(symbol)? {
+        return Ok(Some(asset.asset_kind));
+    }
+
+    let db = WalletDB::get_connection();
+    let collection = db.collection(Event::collection_name());
+
+    let filter = doc! {
+        "$or": [
+            {
+                "symbol": symbol,
+                "eventType": {
+                    "$in": ["stock-operation", "fii-operation", "tesouro-direto-operation"]
+                }
+            },
+            { "detail.newSymbol": symbol }
+        ]
+    };
+
+    let event = match collection.find_one(filter, None)? {
+        Some(doc) => Event::from_doc(doc)?,
+        None => return Ok(None),
+    };
+
+    Ok(Some(match event.detail {
+        EventDetail::StockOperation(operation) => operation.asset_kind,
+        EventDetail::FIIOperation(operation) => operation.asset_kind,
+        EventDetail::TesouroDiretoOperation(operation) => operation.asset_kind,
+        _ => AssetKind::Stock,
+    }))
+}
+
+/// Lists the events affecting a symbol in the (from, until] range, ordered by time.
+/// Besides the symbol's own events, this includes corporate actions that move
+/// holdings from other symbols into it.
+pub fn get_events_for_symbol(
+    symbol: &str,
+    portfolio_oid: Option<String>,
+    broker_oid: Option<String>,
+    from: DateTime<Utc>,
+    until: DateTime<Utc>,
+) -> WalletResult<Vec<Event>> {
+    let db = WalletDB::get_connection();
+    let collection = db.collection(Event::collection_name());
+
+    let mut filter = doc! {
+        "$and": [
+            {
+                "$or": [
+                    { "symbol": symbol },
+                    { "detail.newSymbol": symbol }
+                ]
+            },
+            {
+                "time": {
+                    "$lte": until.to_rfc3339()
+                }
+            },
+            {
+                "time": {
+                    "$gt": from.to_rfc3339()
+                }
+            }
+        ]
+    };
+
+    if let Some(portfolio_oid) = portfolio_oid {
+        filter
+            .get_array_mut("$and")
+            .unwrap()
+            .push(Bson::Document(scope_filter(
+                &["detail.portfolios"],
+                portfolios_in(&portfolio_oid)?,
+            )));
+    }
+
+    if let Some(broker_oid) = broker_oid {
+        filter
+            .get_array_mut("$and")
+            .unwrap()
+            .push(Bson::Document(scope_filter(
+                &BROKER_FIELDS,
+                Bson::String(broker_oid),
+            )));
+    }
+
+    let options = FindOptions::builder().sort(doc! { "time": 1 });
+    let cursor = collection.find(filter, options.build())?;
+
+    cursor
+        .filter_map(Result::ok)
+        .map(Event::from_doc)
+        .collect::<WalletResult<Vec<Event>>>()
+}
+
+/// Fields that tie an event to a broker. Custody transfers involve two of them.
+pub const BROKER_FIELDS: [&str; 3] = ["detail.broker", "detail.fromBroker", "detail.toBroker"];
+
+fn field_matches(fields: &[&str], value: Bson) -> Vec<Bson> {
+    fields
+        .iter()
+        .map(|field| {
+            let mut document = Document::new();
+            document.insert(*field, value.clone());
+            Bson::Document(document)
+        })
+        .collect()
+}
+
+/// Events of a portfolio include those of all portfolios nested under it.
+pub fn portfolios_in(oid: &str) -> WalletResult<Bson> {
+    Ok(Bson::Document(doc! { "$in": Portfolio::subtree(oid)? }))
+}
+
+/// Corporate actions are not registered per portfolio or broker, so they are
+/// always part of the scope.
+fn scope_filter(fields: &[&str], value: Bson) -> Document {
+    let mut alternatives = field_matches(fields, value);
+    alternatives.push(Bson::Document(
+        doc! { "eventType": { "$in": CORPORATE_ACTIONS.to_vec() } },
+    ));
+
+    doc! { "$or": alternatives }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listing_filter() {
+        let options = ListingOptions {
+            event_type: Some("stock-operation".to_string()),
+            broker: Some("xp".to_string()),
+            q: Some("petr".to_string()),
+            ..Default::default()
+        };
+
+        let filter = Event::listing_filter(&options).unwrap();
+        assert_eq!(filter.get_str("eventType").unwrap(), "stock-operation");
+        assert!(filter.contains_key("$or"));
+
+        let broker = filter.get_array("$and").unwrap()[0]
+            .as_document()
+            .unwrap()
+            .get_array("$or")
+            .unwrap();
+        assert_eq!(broker.len(), BROKER_FIELDS.len());
+        assert_eq!(
+            broker[1],
+            Bson::Document(doc! { "detail.fromBroker": "xp" })
+        );
+    }
+}
@@ -25,5 +25,5 @@ pub struct FIIOperation {
 #[openapi]
 #[get("/fiis/position/<symbol>")]
 pub fn get_fii_position_by_symbol(symbol: String) -> WalletResult<Json<Position>> {
-    Position::calculate_for_symbol(&symbol, None).map(Json)
+    Position::calculate_for_symbol(&symbol, None, None).map(Json)
 }
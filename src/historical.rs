@@ -6,9 +6,14 @@ use rocket_okapi::openapi;
 use serde::{Deserialize, Serialize};
 use yahoo_finance::{history, Bar};
 
+use crate::asset::Asset;
+use crate::benchmark::PRICE_BENCHMARKS;
 use crate::error::{BackendError, WalletResult};
 use crate::event::get_distinct_symbols;
 use crate::scheduling::LockMap;
+use crate::tesouro::{
+    get_bond_for_symbol, get_bond_symbols, BondPrice, TesouroDiretoOperation, BOND_FRACTIONS,
+};
 use crate::walletdb::{Queryable, WalletDB};
 
 #[cfg(not(test))]
@@ -53,6 +58,38 @@ impl From<Bar> for AssetDay {
     }
 }
 
+impl From<BondPrice> for AssetDay {
+    fn from(price: BondPrice) -> AssetDay {
+        // Bond positions are kept in hundredths of a bond.
+        let price_per_fraction = price.unit_price / BOND_FRACTIONS;
+        AssetDay {
+            symbol: String::new(),
+            time: price.time,
+            open: price_per_fraction,
+            high: price_per_fraction,
+            low: price_per_fraction,
+            close: price_per_fraction,
+            volume: 0,
+        }
+    }
+}
+
+/// Where the prices for a symbol come from. Finding that out takes a query, so
+/// callers that need prices for many days should do it only once.
+pub enum PriceSource {
+    Yahoo,
+    Bond(TesouroDiretoOperation),
+}
+
+impl PriceSource {
+    pub fn for_symbol(symbol: &str) -> WalletResult<PriceSource> {
+        Ok(match get_bond_for_symbol(symbol)? {
+            Some(bond) => PriceSource::Bond(bond),
+            None => PriceSource::Yahoo,
+        })
+    }
+}
+
 /// # Triggers a full refresh of historical data
 ///
 /// Triggers a full refresh of historical price data for all assets present in the
@@ -69,6 +106,11 @@ pub fn refresh_historicals() -> WalletResult<()> {
 #[openapi]
 #[post("/historicals/refresh/<symbol>")]
 pub fn refresh_historical_for_symbol(symbol: String) -> WalletResult<()> {
+    // Tesouro Direto bonds are not on Yahoo, their prices are imported locally.
+    if let PriceSource::Bond(_) = PriceSource::for_symbol(&symbol)? {
+        return Ok(());
+    }
+
     do_refresh_for_symbol(&symbol)
 }
 
@@ -76,7 +118,19 @@ pub struct Historical {}
 
 impl Historical {
     pub fn refresh_all() -> WalletResult<()> {
-        let symbols = get_distinct_symbols(None)?;
+        // Tesouro Direto bonds are not on Yahoo, their prices are imported locally.
+        let bond_symbols = get_bond_symbols()?;
+        let mut symbols = get_distinct_symbols(None, None)?
+            .into_iter()
+            .filter(|symbol| !bond_symbols.contains(symbol))
+            .collect::<Vec<String>>();
+
+        // Benchmarks are not in any event, but need history for comparisons.
+        symbols.extend(
+            PRICE_BENCHMARKS
+                .iter()
+                .map(|(_, symbol)| symbol.to_string()),
+        );
 
         symbols
             .into_par_iter()
@@ -101,13 +155,32 @@ impl Historical {
             f64::NAN
         };
 
-        PriceCache::update_current_price(symbol, price);
+        // A missing price may show up later, like bond prices once imported.
+        if price.is_finite() {
+            PriceCache::update_current_price(symbol, price);
+        }
 
         price
     }
 
     #[cfg(not(test))]
     pub fn get_for_day_with_fallback(symbol: &str, date: Date<Utc>) -> WalletResult<AssetDay> {
+        let source = PriceSource::for_symbol(symbol)?;
+        Historical::get_for_day_from_source(symbol, &source, date)
+    }
+
+    #[cfg(not(test))]
+    pub fn get_for_day_from_source(
+        symbol: &str,
+        source: &PriceSource,
+        date: Date<Utc>,
+    ) -> WalletResult<AssetDay> {
+        if let PriceSource::Bond(bond) = source {
+            let mut asset_day = AssetDay::from(BondPrice::get_for_day_with_fallback(bond, date)?);
+            asset_day.symbol = symbol.to_string();
+            return Ok(asset_day);
+        }
+
         let db = WalletDB::get_connection();
         let historical = db.collection("historical");
 
@@ -138,6 +211,15 @@ impl Historical {
     }
 }
 
+/// Indexes, like ^BVSP, are already Yahoo symbols.
+fn yahoo_symbol(symbol: &str) -> WalletResult<String> {
+    if symbol.starts_with('^') {
+        return Ok(symbol.to_string());
+    }
+
+    Asset::yahoo_symbol(symbol)
+}
+
 #[tokio::main]
 async fn do_refresh_for_symbol(symbol: &str) -> WalletResult<()> {
     // Ensure we do not try to refresh the same symbol more than once at a time.
@@ -172,7 +254,7 @@ async fn do_refresh_for_symbol(symbol: &str) -> WalletResult<()> {
         return Ok(());
     }
 
-    let data = history::retrieve_range(&format!("{}.SA", symbol), since, Some(yesterday)).await;
+    let data = history::retrieve_range(&yahoo_symbol(symbol)?, since, Some(yesterday)).await;
 
     // HACK: yahoo-finance-rs will fail on queries for days with no data
     // and it doesn't provide a good way of understanding what kind of error
@@ -1,5 +1,5 @@
 use crate::error::WalletResult;
-use crate::historical::{AssetDay, Historical};
+use crate::historical::{AssetDay, Historical, PriceSource};
 use chrono::{Date, Utc};
 
 impl Historical {
@@ -17,6 +17,14 @@ impl Historical {
         Ok(asset_day)
     }
 
+    pub fn get_for_day_from_source(
+        symbol: &str,
+        _source: &PriceSource,
+        date: Date<Utc>,
+    ) -> WalletResult<AssetDay> {
+        Historical::get_for_day_with_fallback(symbol, date)
+    }
+
     #[tokio::main]
     pub async fn current_price_for_symbol(_symbol: String) -> f64 {
         9.0
@@ -0,0 +1,25 @@
+use chrono::{DateTime, Utc};
+use rocket_okapi::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Income {
+    pub gross_amount: f64,
+
+    #[serde(default)]
+    pub withholding: f64,
+
+    pub payment_date: DateTime<Utc>,
+
+    pub broker: Option<String>,
+
+    #[serde(default = "Vec::<String>::new")]
+    pub portfolios: Vec<String>,
+}
+
+impl Income {
+    pub fn net_amount(&self) -> f64 {
+        self.gross_amount - self.withholding
+    }
+}
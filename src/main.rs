@@ -10,30 +10,51 @@ extern crate rocket_okapi;
 extern crate rocket_cors;
 use rocket_okapi::swagger_ui::*;
 
+mod allocation;
+mod asset;
+mod benchmark;
 mod broker;
+mod config;
 #[macro_use]
 mod error;
 mod event;
 mod fii;
 mod historical;
+mod income;
 mod operation;
 mod portfolio;
 mod position;
 mod price_cache;
+mod rebalance;
 mod rest;
+mod returns;
+mod risk;
 mod scheduling;
 mod stock;
+mod tax;
+mod tesouro;
+mod validation;
 mod walletdb;
 mod x_response_time;
 
+use allocation::*;
+use asset::*;
+use benchmark::*;
 use broker::*;
+use config::WalletConfig;
 use event::*;
 use fii::*;
 use historical::*;
 use portfolio::*;
+use position::*;
 use price_cache::PriceCache;
+use rebalance::*;
+use returns::*;
+use risk::*;
 use scheduling::Scheduler;
 use stock::*;
+use tax::*;
+use tesouro::*;
 use walletdb::WalletDB;
 use x_response_time::RequestTimer;
 
@@ -47,12 +68,19 @@ fn main() {
         .mount(
             "/api/v1/",
             routes_with_openapi![
+                // Asset
+                add_asset,
+                get_assets,
+                get_asset_by_oid,
+                update_asset_by_oid,
+                delete_asset_by_oid,
                 // Broker
                 add_broker,
                 get_brokers,
                 get_broker_by_oid,
                 update_broker_by_oid,
                 delete_broker_by_oid,
+                broker_positions,
                 // Events
                 add_event,
                 get_events,
@@ -63,13 +91,27 @@ fn main() {
                 get_stock_position_by_symbol,
                 // FII
                 get_fii_position_by_symbol,
+                // Tesouro Direto
+                get_tesouro_position_by_symbol,
+                import_bond_prices,
+                get_bond_prices,
+                // Benchmarks
+                import_index_rates,
+                get_index_rates,
                 // Historical
                 refresh_historicals,
                 refresh_historical_for_symbol,
                 // Performance
                 performance,
+                portfolio_xirr,
+                portfolio_risk,
                 // Position
                 positions,
+                position_lots,
+                position_xirr,
+                // Tax
+                monthly_tax,
+                annual_tax_report,
                 // Portfolio
                 add_portfolio,
                 get_portfolios,
@@ -77,6 +119,8 @@ fn main() {
                 update_portfolio_by_oid,
                 delete_portfolio_by_oid,
                 portfolio_positions,
+                portfolio_rebalance,
+                portfolio_allocation,
             ],
         )
         .mount(
@@ -86,7 +130,14 @@ fn main() {
                 ..Default::default()
             }),
         )
+        .register(catchers![
+            error::bad_request,
+            error::not_found,
+            error::unprocessable_entity,
+            error::internal_error,
+        ])
         .attach(RequestTimer)
+        .attach(WalletConfig::fairing())
         .attach(WalletDB::fairing())
         .attach(PriceCache::fairing())
         .attach(Scheduler::fairing())
@@ -1,11 +1,14 @@
 use mongodb::bson::doc;
 use rocket_okapi::JsonSchema;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
+use std::ops::AddAssign;
 
 use crate::walletdb::Queryable;
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum AssetKind {
     Stock,
@@ -20,6 +23,134 @@ pub enum OperationKind {
     Sale,
 }
 
+/// Fees charged on an operation, as itemized on the brokerage note.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(from = "FeesInput", into = "FeesOutput")]
+pub struct Fees {
+    pub corretagem: f64,
+    pub emolumentos: f64,
+    pub liquidacao: f64,
+    pub iss: f64,
+    pub irrf: f64,
+}
+
+impl Fees {
+    pub fn total(&self) -> f64 {
+        self.corretagem + self.emolumentos + self.liquidacao + self.iss + self.irrf
+    }
+
+    /// Fees that make up the cost of the operation. IRRF is left out, as it is
+    /// an advance on income tax, deducted when paying the DARF.
+    pub fn cost(&self) -> f64 {
+        self.total() - self.irrf
+    }
+}
+
+impl AddAssign for Fees {
+    fn add_assign(&mut self, other: Fees) {
+        self.corretagem += other.corretagem;
+        self.emolumentos += other.emolumentos;
+        self.liquidacao += other.liquidacao;
+        self.iss += other.iss;
+        self.irrf += other.irrf;
+    }
+}
+
+impl JsonSchema for Fees {
+    fn schema_name() -> String {
+        "Fees".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        FeesInput::json_schema(gen)
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum FeesInput {
+    // Older events carry a single number, which we take as brokerage.
+    Total(f64),
+    Itemized {
+        #[serde(default)]
+        corretagem: f64,
+        #[serde(default)]
+        emolumentos: f64,
+        #[serde(default)]
+        liquidacao: f64,
+        #[serde(default)]
+        iss: f64,
+        #[serde(default)]
+        irrf: f64,
+    },
+}
+
+/// Fees as they go out, with their total worked out. The total is ignored when
+/// reading them back.
+#[derive(Serialize)]
+struct FeesOutput {
+    corretagem: f64,
+    emolumentos: f64,
+    liquidacao: f64,
+    iss: f64,
+    irrf: f64,
+    total: f64,
+}
+
+impl From<Fees> for FeesOutput {
+    fn from(fees: Fees) -> FeesOutput {
+        FeesOutput {
+            corretagem: fees.corretagem,
+            emolumentos: fees.emolumentos,
+            liquidacao: fees.liquidacao,
+            iss: fees.iss,
+            irrf: fees.irrf,
+            total: fees.total(),
+        }
+    }
+}
+
+impl From<FeesInput> for Fees {
+    fn from(input: FeesInput) -> Fees {
+        match input {
+            FeesInput::Total(corretagem) => Fees {
+                corretagem,
+                ..Fees::default()
+            },
+            FeesInput::Itemized {
+                corretagem,
+                emolumentos,
+                liquidacao,
+                iss,
+                irrf,
+            } => Fees {
+                corretagem,
+                emolumentos,
+                liquidacao,
+                iss,
+                irrf,
+            },
+        }
+    }
+}
+
+/// How a sale picks the lots it consumes.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LotMatching {
+    FIFO,
+    LIFO,
+    /// Lots named by the ids of the purchases that opened them. Anything sold
+    /// beyond them is matched by FIFO.
+    Specific(Vec<String>),
+}
+
+impl Default for LotMatching {
+    fn default() -> Self {
+        LotMatching::FIFO
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct BaseOperation {
@@ -27,7 +158,7 @@ pub struct BaseOperation {
     pub quantity: i64,
 
     #[serde(default)]
-    pub fees: f64,
+    pub fees: Fees,
 
     #[serde(rename = "type")]
     pub kind: OperationKind,
@@ -36,6 +167,10 @@ pub struct BaseOperation {
 
     #[serde(default = "Vec::<String>::new")]
     pub portfolios: Vec<String>,
+
+    /// Only meaningful for sales.
+    #[serde(default)]
+    pub lot_matching: LotMatching,
 }
 
 impl Queryable for BaseOperation {
@@ -43,3 +178,44 @@ impl Queryable for BaseOperation {
         "operations"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use mongodb::bson::{from_bson, to_bson, Bson};
+
+    use super::*;
+
+    #[test]
+    fn fees() {
+        // Older events carry a single number.
+        let fees = from_bson::<Fees>(Bson::Double(4.9)).expect("Failed to parse fees");
+        assert_eq!(
+            fees,
+            Fees {
+                corretagem: 4.9,
+                ..Fees::default()
+            }
+        );
+
+        let fees = from_bson::<Fees>(Bson::Document(doc! {
+            "corretagem": 4.9,
+            "emolumentos": 0.15,
+            "liquidacao": 0.35,
+            "irrf": 0.05,
+        }))
+        .expect("Failed to parse fees");
+        assert_relative_eq!(fees.iss, 0.0);
+        assert_relative_eq!(fees.total(), 5.45);
+        assert_relative_eq!(fees.cost(), 5.4);
+
+        // The total goes out with the fees, and reading them back ignores it.
+        let bson = to_bson(&fees).expect("Failed to serialize fees");
+        let total = bson
+            .as_document()
+            .and_then(|doc| doc.get_f64("total").ok())
+            .expect("Missing total");
+        assert_relative_eq!(total, 5.45);
+        assert_eq!(from_bson::<Fees>(bson).expect("Failed to parse fees"), fees);
+    }
+}
@@ -1,14 +1,19 @@
 use chrono::{Date, Utc};
+use mongodb::bson::doc;
 use rocket::request::Form;
 use rocket_contrib::json::Json;
 use rocket_okapi::{openapi, JsonSchema};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
-use crate::error::WalletResult;
+use crate::benchmark::benchmark_series;
+use crate::config::SnapshotFrequency;
+use crate::error::{BackendError, WalletResult};
 use crate::operation::OperationKind;
 use crate::position::Position;
+use crate::rebalance::TargetAllocation;
 use crate::rest::*;
-use crate::walletdb::Queryable;
+use crate::walletdb::{get_one, Queryable, WalletDB};
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
@@ -16,6 +21,102 @@ pub struct Portfolio {
     #[serde(alias = "_id")]
     pub id: Option<String>,
     pub name: String,
+
+    /// Portfolio this one is nested under, if any. Views of a portfolio roll up
+    /// all of its descendants.
+    #[serde(default)]
+    pub parent: Option<String>,
+
+    #[serde(default)]
+    pub targets: TargetAllocation,
+}
+
+impl Portfolio {
+    /// Lists the given portfolio and all of its descendants.
+    pub fn subtree(oid: &str) -> WalletResult<Vec<String>> {
+        let db = WalletDB::get_connection();
+        let collection = db.collection(Portfolio::collection_name());
+
+        let mut oids = vec![oid.to_string()];
+        let mut pending = oids.clone();
+        while !pending.is_empty() {
+            let filter = doc! { "parent": { "$in": pending } };
+
+            // Guard against cycles, in case a portfolio was made its own ancestor.
+            pending = Portfolio::from_docs(collection.find(filter, None)?)?
+                .into_iter()
+                .filter_map(|portfolio| portfolio.id)
+                .filter(|id| !oids.contains(id))
+                .collect();
+
+            oids.extend(pending.iter().cloned());
+        }
+
+        Ok(oids)
+    }
+
+    /// Lists the given portfolio and all of its ancestors.
+    pub fn ancestors(oid: &str) -> WalletResult<Vec<String>> {
+        let mut oids = Vec::<String>::new();
+        let mut next = Some(oid.to_string());
+
+        // Guard against cycles here as well.
+        while let Some(oid) = next.filter(|oid| !oids.contains(oid)) {
+            next = match get_one::<Portfolio>(oid.clone()) {
+                Ok(portfolio) => portfolio.parent,
+                Err(BackendError::NotFound) => None,
+                Err(e) => return Err(e),
+            };
+            oids.push(oid);
+        }
+
+        Ok(oids)
+    }
+
+    /// A parent must exist, and cannot be the portfolio itself or any portfolio
+    /// nested under it, which would make a cycle.
+    fn check_parent(&self, oid: Option<&str>) -> WalletResult<()> {
+        let parent = match &self.parent {
+            Some(parent) => parent,
+            None => return Ok(()),
+        };
+
+        match get_one::<Portfolio>(parent.clone()) {
+            Ok(_) => (),
+            Err(BackendError::NotFound) => {
+                return Err(BackendError::validation(
+                    "parent",
+                    &format!("Unknown portfolio {}", parent),
+                ))
+            }
+            Err(e) => return Err(e),
+        }
+
+        if let Some(oid) = oid {
+            if Portfolio::subtree(oid)?.contains(parent) {
+                return Err(BackendError::validation(
+                    "parent",
+                    "A portfolio cannot be nested under itself",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Snapshots of a portfolio roll up its descendants, so they go stale for the
+    /// whole chain of ancestors whenever a portfolio joins or leaves it.
+    fn invalidate_snapshots(oid: Option<&str>) -> WalletResult<()> {
+        if let Some(oid) = oid {
+            let db = WalletDB::get_connection();
+            db.collection(Position::collection_name()).delete_many(
+                doc! { "portfolio": { "$in": Portfolio::ancestors(oid)? } },
+                None,
+            )?;
+        }
+
+        Ok(())
+    }
 }
 
 /// # List positions
@@ -41,6 +142,18 @@ fn get_portfolio_positions(
     options: Option<Form<ListingOptions>>,
 ) -> WalletResult<Rest<Json<Vec<Position>>>> {
     let mut result = Position::get_all_for_portfolio(id)?;
+
+    // Positions are calculated rather than stored, so they are filtered here.
+    if let Some(options) = &options {
+        if let Some(symbol) = &options.symbol {
+            result.retain(|position| &position.symbol == symbol);
+        }
+        if let Some(q) = &options.q {
+            let q = q.to_uppercase();
+            result.retain(|position| position.symbol.contains(&q));
+        }
+    }
+
     let count = result.len();
 
     if let Some(options) = options {
@@ -80,16 +193,15 @@ pub struct PerformanceSnapshot {
     name: String,
     reference: f64,
     percentual_gain: f64,
+
+    /// Requested benchmarks, rebased to 100 on the first date like `reference`.
+    #[serde(flatten)]
+    benchmarks: BTreeMap<String, f64>,
 }
 
-/// # Obtain cash-flow-adjusted historical performance
-///
-/// Returns an array with weekly snapshots of portfolio performance adjusted
-/// by purchases and sales on that week. This gives us performance measurements
-/// that can be compared with indexes or funds performance.
-#[openapi]
-#[get("/portfolios/performance?<oid>")]
-pub fn performance(oid: Option<String>) -> WalletResult<Json<Vec<PerformanceSnapshot>>> {
+/// Chains the returns between stored snapshots, adjusted by purchases and sales
+/// made in between, into a series that starts at 100.
+pub fn reference_series(oid: Option<String>) -> WalletResult<Vec<(Date<Utc>, f64)>> {
     let snapshots = Position::get_history_for_portfolio(oid, None)?;
     let mut dates = snapshots.keys().collect::<Vec<&Date<Utc>>>();
     dates.sort();
@@ -98,16 +210,13 @@ pub fn performance(oid: Option<String>) -> WalletResult<Json<Vec<PerformanceSnap
     struct AggregatePosition {
         cost_basis: f64,
         current_value: f64,
+        lending_income: f64,
         operations_adjustment: f64,
     }
 
-    let mut performance_snapshots = vec![];
+    let mut series = vec![];
     let mut previous_aggregate: Option<AggregatePosition> = None;
-    let mut snapshot = PerformanceSnapshot {
-        name: String::new(),
-        reference: 100.,
-        percentual_gain: 0.,
-    };
+    let mut reference: f64 = 100.;
 
     for d in dates {
         let positions = snapshots.get(d).unwrap();
@@ -115,12 +224,14 @@ pub fn performance(oid: Option<String>) -> WalletResult<Json<Vec<PerformanceSnap
             AggregatePosition {
                 cost_basis: 0.,
                 current_value: 0.,
+                lending_income: 0.,
                 operations_adjustment: 0.,
             },
             |acc, pos| {
                 let mut acc = acc;
                 acc.cost_basis += pos.cost_basis;
                 acc.current_value += pos.current_price * pos.quantity as f64;
+                acc.lending_income += pos.lending_income;
 
                 // We need to adjust our aggregate numbers for the operations
                 // that happened since our last snapshot, so that they do
@@ -138,20 +249,84 @@ pub fn performance(oid: Option<String>) -> WalletResult<Json<Vec<PerformanceSnap
 
         let mut percent_change = 0.;
         if let Some(previous_aggregate) = previous_aggregate {
-            let adjusted_current_value = aggregate.current_value - aggregate.operations_adjustment;
+            // Lending income received since the last snapshot counts towards the return.
+            let adjusted_current_value = aggregate.current_value - aggregate.operations_adjustment
+                + aggregate.lending_income
+                - previous_aggregate.lending_income;
             percent_change = (adjusted_current_value - previous_aggregate.current_value)
                 / previous_aggregate.current_value;
         }
 
-        snapshot.name = d.naive_utc().to_string();
-        snapshot.reference += snapshot.reference.abs() * percent_change;
-        snapshot.percentual_gain = snapshot.reference - 100.;
-
-        performance_snapshots.push(snapshot.clone());
+        reference += reference.abs() * percent_change;
+        series.push((*d, reference));
 
         previous_aggregate = Some(aggregate);
     }
 
+    Ok(series)
+}
+
+/// # Obtain cash-flow-adjusted historical performance
+///
+/// Returns an array with snapshots of portfolio performance adjusted by purchases
+/// and sales since the previous snapshot. This gives us performance measurements
+/// that can be compared with indexes or funds performance. The stored snapshots
+/// can be resampled to a `daily`, `weekly` or `monthly` frequency, keeping the
+/// last one of each period. A comma-separated list of benchmarks, like
+/// `IBOV,CDI,IPCA`, adds their series to each snapshot.
+#[openapi]
+#[get("/portfolios/performance?<oid>&<benchmark>&<frequency>")]
+pub fn performance(
+    oid: Option<String>,
+    benchmark: Option<String>,
+    frequency: Option<SnapshotFrequency>,
+) -> WalletResult<Json<Vec<PerformanceSnapshot>>> {
+    let mut series = reference_series(oid)?;
+
+    // Returns are always chained over all stored snapshots, resampling only picks
+    // the first snapshot and the last one of each period.
+    if let Some(frequency) = frequency {
+        let last = series.len().saturating_sub(1);
+        series = series
+            .iter()
+            .enumerate()
+            .filter(|(i, (date, _))| {
+                *i == 0
+                    || *i == last
+                    || frequency.period(*date) != frequency.period(series[i + 1].0)
+            })
+            .map(|(_, point)| *point)
+            .collect();
+    }
+
+    let benchmark_dates = series
+        .iter()
+        .map(|(date, _)| *date)
+        .collect::<Vec<Date<Utc>>>();
+    let mut performance_snapshots = series
+        .into_iter()
+        .map(|(date, reference)| PerformanceSnapshot {
+            name: date.naive_utc().to_string(),
+            reference,
+            percentual_gain: reference - 100.,
+            benchmarks: BTreeMap::new(),
+        })
+        .collect::<Vec<PerformanceSnapshot>>();
+
+    if let Some(benchmark) = benchmark {
+        for name in benchmark
+            .split(',')
+            .map(str::trim)
+            .filter(|n| !n.is_empty())
+        {
+            // Benchmarks without data for the period are left out.
+            let series = benchmark_series(name, &benchmark_dates)?.unwrap_or_default();
+            for (snapshot, value) in performance_snapshots.iter_mut().zip(series) {
+                snapshot.benchmarks.insert(name.to_string(), value);
+            }
+        }
+    }
+
     Ok(Json(performance_snapshots))
 }
 
@@ -159,6 +334,10 @@ impl Queryable for Portfolio {
     fn collection_name() -> &'static str {
         "portfolios"
     }
+
+    fn search_fields() -> &'static [&'static str] {
+        &["name"]
+    }
 }
 
 /// # Add a portfolio
@@ -167,6 +346,8 @@ impl Queryable for Portfolio {
 #[openapi]
 #[post("/portfolios", data = "<portfolio>")]
 pub fn add_portfolio(portfolio: Json<Portfolio>) -> WalletResult<Json<Portfolio>> {
+    portfolio.check_parent(None)?;
+    Portfolio::invalidate_snapshots(portfolio.parent.as_deref())?;
     api_add(portfolio)
 }
 
@@ -200,6 +381,13 @@ pub fn update_portfolio_by_oid(
     oid: String,
     portfolio: Json<Portfolio>,
 ) -> WalletResult<Json<Portfolio>> {
+    let previous = get_one::<Portfolio>(oid.clone())?;
+    portfolio.check_parent(Some(&oid))?;
+    if previous.parent != portfolio.parent {
+        Portfolio::invalidate_snapshots(previous.parent.as_deref())?;
+        Portfolio::invalidate_snapshots(portfolio.parent.as_deref())?;
+    }
+
     api_update::<Portfolio>(oid, portfolio)
 }
 
@@ -209,5 +397,106 @@ pub fn update_portfolio_by_oid(
 #[openapi]
 #[delete("/portfolios/<oid>")]
 pub fn delete_portfolio_by_oid(oid: String) -> WalletResult<Json<Portfolio>> {
+    Portfolio::invalidate_snapshots(Some(&oid))?;
     api_delete::<Portfolio>(oid)
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use rusty_fork::rusty_fork_test;
+
+    use super::*;
+    use crate::event::{Event, EventDetail};
+    use crate::operation::{AssetKind, BaseOperation, Fees, LotMatching};
+    use crate::scheduling::LockMap;
+    use crate::stock::StockOperation;
+    use crate::walletdb::insert_one;
+
+    fn portfolio(name: &str, parent: Option<String>) -> Portfolio {
+        add_portfolio(Json(Portfolio {
+            id: None,
+            name: name.to_string(),
+            parent,
+            targets: TargetAllocation::default(),
+        }))
+        .expect("Failed to insert portfolio")
+        .into_inner()
+    }
+
+    fn snapshot_count(portfolio: &Portfolio) -> i64 {
+        WalletDB::get_connection()
+            .collection(Position::collection_name())
+            .count_documents(doc! { "portfolio": portfolio.id.clone().unwrap() }, None)
+            .expect("Failed to count snapshots")
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn reparenting() {
+            WalletDB::init_client("mongodb://localhost:27017/");
+
+            let parent = portfolio("Parent", None);
+            let child = portfolio("Child", parent.id.clone());
+
+            insert_one(Event {
+                id: None,
+                symbol: "NEST3".to_string(),
+                time: Utc.ymd(2020, 1, 6).and_hms(12, 0, 0),
+                detail: EventDetail::StockOperation(StockOperation {
+                    asset_kind: AssetKind::Stock,
+                    operation: BaseOperation {
+                        kind: OperationKind::Purchase,
+                        broker: None,
+                        portfolios: vec![child.id.clone().unwrap()],
+                        price: 10.0,
+                        quantity: 100,
+                        fees: Fees::default(),
+                        lot_matching: LotMatching::default(),
+                    },
+                }),
+            })
+            .expect("Failed to insert event");
+
+            // The parent rolls up the child's holdings.
+            let position = Position::calculate_for_symbol("NEST3", parent.id.clone(), None)
+                .expect("Something went wrong");
+            drop(LockMap::lock(Position::collection_name(), "NEST3"));
+            assert_eq!(position.quantity, 100);
+            assert!(snapshot_count(&parent) > 0);
+
+            // Moving the child elsewhere leaves the parent's snapshots stale.
+            let other = portfolio("Other", None);
+            let mut moved = child.clone();
+            moved.parent = other.id.clone();
+            update_portfolio_by_oid(child.id.clone().unwrap(), Json(moved))
+                .expect("Failed to update portfolio");
+            assert_eq!(snapshot_count(&parent), 0);
+
+            let position = Position::calculate_for_symbol("NEST3", parent.id.clone(), None)
+                .expect("Something went wrong");
+            drop(LockMap::lock(Position::collection_name(), "NEST3"));
+            assert_eq!(position.quantity, 0);
+
+            // Parents must exist, and cannot make a cycle.
+            let invalid_parent = |oid: Option<String>, parent: &str| {
+                let mut portfolio = other.clone();
+                portfolio.parent = Some(parent.to_string());
+                let result = match oid {
+                    Some(oid) => update_portfolio_by_oid(oid, Json(portfolio)),
+                    None => add_portfolio(Json(portfolio)),
+                };
+                match result {
+                    Err(BackendError::Validation { field, .. }) => assert_eq!(field, "parent"),
+                    other => panic!("unexpected result {:?}", other),
+                }
+            };
+            let (child_oid, other_oid) = (child.id.clone().unwrap(), other.id.clone().unwrap());
+            invalid_parent(None, "5f5a5a5a5a5a5a5a5a5a5a5a");
+            invalid_parent(Some(other_oid.clone()), &other_oid);
+            invalid_parent(Some(other_oid.clone()), &child_oid);
+
+            WalletDB::drop_database();
+        }
+    }
+}
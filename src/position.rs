@@ -1,21 +1,24 @@
-use chrono::{Date, DateTime, Datelike, Duration, TimeZone, Utc, Weekday};
+use chrono::{Date, DateTime, Duration, TimeZone, Utc};
 use log::{debug, info, warn};
 use mongodb::bson::{doc, Bson};
 use mongodb::options::{FindOneOptions, FindOptions};
 use rayon::prelude::*;
-use rocket_okapi::JsonSchema;
+use rocket_contrib::json::Json;
+use rocket_okapi::{openapi, JsonSchema};
 use serde::{Deserialize, Serialize};
 use std::cmp::PartialEq;
 use std::collections::HashMap;
 use std::sync::Mutex;
 
+use crate::config::WalletConfig;
 use crate::error::*;
-use crate::event::{get_distinct_symbols, Event, EventDetail};
+use crate::event::{get_distinct_symbols, get_events_for_symbol, Event, EventDetail};
 use crate::fii::FIIOperation;
-use crate::historical::Historical;
-use crate::operation::{BaseOperation, OperationKind};
+use crate::historical::{Historical, PriceSource};
+use crate::operation::{BaseOperation, Fees, LotMatching, OperationKind};
 use crate::scheduling::LockMap;
-use crate::stock::{StockOperation, StockSplitKind};
+use crate::stock::{CustodyTransfer, StockOperation, StockSplitKind};
+use crate::tesouro::TesouroDiretoOperation;
 use crate::walletdb::*;
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
@@ -31,12 +34,162 @@ pub struct Position {
     pub current_price: f64,
     pub gain: f64,
     pub realized: f64,
+    #[serde(default)]
+    pub swing_trade_realized: f64,
+    #[serde(default)]
+    pub day_trade_realized: f64,
+    #[serde(default)]
+    pub fees: Fees,
+    #[serde(default)]
+    pub income: f64,
+    #[serde(default)]
+    pub yield_on_cost: f64,
+    /// Shares currently lent out; still owned, but not available for sale.
+    #[serde(default)]
+    pub lent_quantity: i64,
+    #[serde(default)]
+    pub lending_income: f64,
+    /// Purchases still held, oldest first. Only long holdings are tracked, and
+    /// only when the ledger is enabled.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub lots: Vec<Lot>,
+    /// Result of sales when matched against lots rather than the average price.
+    #[serde(default)]
+    pub lot_realized: f64,
     pub recent_operations: Vec<BaseOperation>,
     pub portfolio: Option<String>,
+    #[serde(default)]
+    pub broker: Option<String>,
+
+    // Day trade purchases and sales that have not been matched yet. Every day
+    // trade is matched by the end of its day and replays always start from a
+    // day boundary, so there is no need to store these.
+    #[serde(skip)]
+    day_trade_purchases: DayTradeLeg,
+    #[serde(skip)]
+    day_trade_sales: DayTradeLeg,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct DayTradeLeg {
+    quantity: i64,
+    amount: f64,
+}
+
+impl DayTradeLeg {
+    fn add(&mut self, quantity: i64, amount: f64) {
+        self.quantity += quantity;
+        self.amount += amount;
+    }
+
+    fn take(&mut self, quantity: i64) -> f64 {
+        let amount = self.amount * quantity as f64 / self.quantity as f64;
+        self.quantity -= quantity;
+        self.amount -= amount;
+        amount
+    }
+}
+
+/// A purchase, or what is left of it, for assets taxed per lot.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Lot {
+    /// Id of the event that opened the lot.
+    pub id: Option<String>,
+    pub time: DateTime<Utc>,
+    pub quantity: i64,
+    pub price: f64,
+    /// Cost of the remaining quantity, fees included.
+    pub cost_basis: f64,
+}
+
+impl Lot {
+    fn take(&mut self, quantity: i64) -> f64 {
+        let cost = self.cost_basis * quantity as f64 / self.quantity as f64;
+        self.quantity -= quantity;
+        self.cost_basis -= cost;
+        cost
+    }
+}
+
+/// Takes the given quantity out of the oldest lots, returning what was taken.
+fn take_lots(lots: &mut Vec<Lot>, quantity: i64) -> Vec<Lot> {
+    let mut taken = Vec::<Lot>::new();
+    let mut remaining = quantity;
+    for lot in lots.iter_mut() {
+        if remaining == 0 {
+            break;
+        }
+
+        let quantity = remaining.min(lot.quantity);
+        taken.push(Lot {
+            quantity,
+            cost_basis: lot.take(quantity),
+            ..lot.clone()
+        });
+        remaining -= quantity;
+    }
+
+    lots.retain(|lot| lot.quantity > 0);
+
+    taken
+}
+
+/// Only whole shares are kept after corporate actions. This also guards against
+/// floating point noise, like ending up with 2.9999999 shares.
+fn whole_shares(exact_quantity: f64) -> f64 {
+    let quantity = exact_quantity.round();
+    if (exact_quantity - quantity).abs() > 1e-6 {
+        exact_quantity.trunc()
+    } else {
+        quantity
+    }
+}
+
+/// Adjusts lots for corporate actions. Fractions are pooled, as B3 does, so the
+/// lots keep as many whole shares as the position, see `apply_split`. Shares left
+/// over from truncating each lot go to the lots with the largest fractions.
+/// Returns the new lots and the cost of the fractions sold.
+fn scale_lots(lots: &[Lot], quantity_factor: f64, cost_factor: f64) -> (Vec<Lot>, f64) {
+    let exact = lots
+        .iter()
+        .map(|lot| lot.quantity as f64 * quantity_factor)
+        .collect::<Vec<f64>>();
+    let mut kept = exact
+        .iter()
+        .map(|quantity| whole_shares(*quantity))
+        .collect::<Vec<f64>>();
+
+    let mut by_fraction = (0..lots.len()).collect::<Vec<usize>>();
+    by_fraction
+        .sort_by(|a, b| Position::float_cmp(&(exact[*b] - kept[*b]), &(exact[*a] - kept[*a])));
+
+    let leftover = whole_shares(exact.iter().sum()) - kept.iter().sum::<f64>();
+    for index in by_fraction.into_iter().take(leftover.round() as usize) {
+        kept[index] += 1.0;
+    }
+
+    let mut fraction_cost = 0.0;
+    let mut scaled = Vec::<Lot>::new();
+    for (index, lot) in lots.iter().enumerate() {
+        let cost_basis = lot.cost_basis * cost_factor * kept[index] / exact[index];
+        fraction_cost += lot.cost_basis * cost_factor - cost_basis;
+
+        if kept[index] >= 1.0 {
+            scaled.push(Lot {
+                quantity: kept[index] as i64,
+                price: lot.price * cost_factor / quantity_factor,
+                cost_basis,
+                ..lot.clone()
+            });
+        }
+    }
+
+    (scaled, fraction_cost)
 }
 
 impl Position {
-    fn new(symbol: &str, portfolio_oid: Option<String>) -> Self {
+    pub fn new(symbol: &str, portfolio_oid: Option<String>, broker_oid: Option<String>) -> Self {
         Position {
             id: None,
             symbol: symbol.to_string(),
@@ -47,8 +200,28 @@ impl Position {
             current_price: 0.0,
             gain: 0.0,
             realized: 0.0,
+            swing_trade_realized: 0.0,
+            day_trade_realized: 0.0,
+            fees: Fees::default(),
+            income: 0.0,
+            yield_on_cost: 0.0,
+            lent_quantity: 0,
+            lending_income: 0.0,
+            lots: Vec::<Lot>::new(),
+            lot_realized: 0.0,
             recent_operations: Vec::<BaseOperation>::new(),
             portfolio: portfolio_oid,
+            broker: broker_oid,
+            day_trade_purchases: DayTradeLeg::default(),
+            day_trade_sales: DayTradeLeg::default(),
+        }
+    }
+
+    fn update_average_price(&mut self) {
+        if self.quantity == 0 {
+            self.average_price = 0.0;
+        } else if self.cost_basis != 0.0 {
+            self.average_price = self.cost_basis / self.quantity as f64;
         }
     }
 
@@ -93,8 +266,12 @@ impl Position {
     }
 
     pub fn cmp_gain(a: &Position, b: &Position) -> std::cmp::Ordering {
-        // The web UI shows gain as a percentage.
-        Position::float_cmp(&(a.gain / a.cost_basis), &(b.gain / b.cost_basis))
+        // The web UI shows gain as a percentage. Short positions have a negative
+        // cost basis, which would flip the sign of their percentage.
+        Position::float_cmp(
+            &(a.gain / a.cost_basis.abs()),
+            &(b.gain / b.cost_basis.abs()),
+        )
     }
 }
 
@@ -104,125 +281,118 @@ impl Queryable for Position {
     }
 }
 
-fn find_all_fridays_between(from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<Date<Utc>> {
-    let mut fridays = Vec::<Date<Utc>>::new();
+fn find_snapshot_dates_between(from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<Date<Utc>> {
+    let frequency = WalletConfig::snapshot_frequency();
+    let mut dates = Vec::<Date<Utc>>::new();
     let mut date = from.date();
     let to = to.date();
 
     while date < to {
-        if date.weekday() == Weekday::Fri {
-            fridays.push(date.with_timezone(&Utc));
-            date = date + Duration::days(7);
-        } else {
-            date = date + Duration::days(1);
+        if frequency.is_snapshot_date(date) {
+            dates.push(date.with_timezone(&Utc));
         }
+        date = date + Duration::days(1);
     }
 
-    fridays
+    dates
+}
+
+/// Snapshots are timed at noon, but hold every event of their day. Replays pick
+/// up from the start of the next day, which also keeps each day's trades together
+/// for `classify_day_trades`.
+fn snapshot_replay_start(snapshot: &Position) -> DateTime<Utc> {
+    snapshot.time.date().succ().and_hms(0, 0, 0)
+}
+
+/// Finds out how much of each operation is a day trade, that is, bought and sold
+/// on the same day at the same broker. Returns the day trade quantity for each event.
+pub fn classify_day_trades(events: &[Event]) -> Vec<i64> {
+    fn operation(event: &Event) -> Option<&BaseOperation> {
+        match &event.detail {
+            EventDetail::StockOperation(StockOperation { operation, .. })
+            | EventDetail::FIIOperation(FIIOperation { operation, .. }) => Some(operation),
+            _ => None,
+        }
+    }
+
+    // Quantities bought and sold for each day and broker.
+    let mut totals = HashMap::<(Date<Utc>, Option<String>), (i64, i64)>::new();
+    for event in events {
+        if let Some(operation) = operation(event) {
+            let (bought, sold) = totals
+                .entry((event.time.date(), operation.broker.clone()))
+                .or_insert((0, 0));
+            match operation.kind {
+                OperationKind::Purchase => *bought += operation.quantity,
+                OperationKind::Sale => *sold += operation.quantity,
+            }
+        }
+    }
+
+    // Whatever was both bought and sold is a day trade, so we hand that out to
+    // the operations in order.
+    let mut remaining = totals
+        .into_iter()
+        .map(|(key, (bought, sold))| (key, (bought.min(sold), bought.min(sold))))
+        .collect::<HashMap<_, _>>();
+
+    events
+        .iter()
+        .map(|event| {
+            operation(event).map_or(0, |operation| {
+                let (bought, sold) = remaining
+                    .get_mut(&(event.time.date(), operation.broker.clone()))
+                    .unwrap();
+                let left = match operation.kind {
+                    OperationKind::Purchase => bought,
+                    OperationKind::Sale => sold,
+                };
+                let quantity = operation.quantity.min(*left);
+                *left -= quantity;
+                quantity
+            })
+        })
+        .collect()
 }
 
 #[tokio::main]
 async fn do_calculate_for_symbol(
     symbol: String,
     portfolio_oid: Option<String>,
+    broker_oid: Option<String>,
 ) -> WalletResult<Position> {
     // Ensure we do not try to calculate for the same symbol more than once at a time.
     // Create it here so it is locked even before the thread gets to run, to avoid
     // races with callers of this function or multiple calls of this function.
     let guard = LockMap::lock(Position::collection_name(), &symbol);
 
-    let db = WalletDB::get_connection();
-    let collection = db.collection(Event::collection_name());
-
     let mut date_from = Utc.timestamp(61, 0);
 
     // If we already have a bunch of position snapshots, we pick up
     // from the last one rather than starting from scratch.
-    let mut position = Position::last(&symbol, portfolio_oid.clone())
+    let mut position = Position::last(&symbol, portfolio_oid.clone(), broker_oid.clone())
         .map(|pos| {
-            date_from = pos.time.with_timezone(&Utc);
+            date_from = snapshot_replay_start(&pos);
             pos
         })
-        .unwrap_or_else(|| Position::new(&symbol, portfolio_oid.clone()));
-    let mut filter = doc! {
-        "$and": [
-            { "symbol": &symbol },
-            {
-                "time": {
-                    "$lte": Utc::today().and_hms(23, 59, 59).to_rfc3339()
-                }
-            },
-            {
-                "time": {
-                    "$gt": date_from.to_rfc3339()
-                }
-            }
-        ]
-    };
+        .unwrap_or_else(|| Position::new(&symbol, portfolio_oid.clone(), broker_oid.clone()));
 
-    if let Some(portfolio_oid) = portfolio_oid {
-        filter
-            .get_array_mut("$and")
-            .unwrap()
-            .push(Bson::Document(doc! {
-                "detail.portfolios": portfolio_oid
-            }));
-    }
+    let events = get_events_for_symbol(
+        &symbol,
+        portfolio_oid,
+        broker_oid,
+        date_from,
+        Utc::today().and_hms(23, 59, 59),
+    )?;
 
-    let options = FindOptions::builder().sort(doc! { "time": 1 });
-    let cursor = collection.find(filter, options.build())?;
+    let day_trades = classify_day_trades(&events);
 
     let mut references = Vec::<Position>::new();
-    for document in cursor {
-        if let Ok(document) = document {
-            let event = Event::from_doc(document)?;
-
-            position.time = event.time;
-
-            match event.detail {
-                EventDetail::StockOperation(StockOperation { operation, .. })
-                | EventDetail::FIIOperation(FIIOperation { operation, .. }) => {
-                    match operation.kind {
-                        OperationKind::Purchase => {
-                            position.cost_basis += operation.price * operation.quantity as f64;
-                            position.quantity += operation.quantity;
-                        }
-                        OperationKind::Sale => {
-                            /* When selling we need to use the average price at the moment
-                             * of the sale for the average calculation to work. We may
-                             * take out too little if the current price is lower or too
-                             * much, otherwise.
-                             */
-                            let cost_price = position.cost_basis / position.quantity as f64;
-                            position.cost_basis -= cost_price * operation.quantity as f64;
-                            position.quantity -= operation.quantity;
-
-                            position.realized += operation.quantity as f64 * operation.price
-                                - operation.quantity as f64 * cost_price;
-                        }
-                    }
-
-                    if position.quantity != 0 && position.cost_basis != 0.0 {
-                        position.average_price = position.cost_basis / position.quantity as f64;
-                    }
-
-                    position.recent_operations.push(operation.clone());
-                }
-                EventDetail::StockSplit(split) => match split.split_kind {
-                    StockSplitKind::Split => {
-                        position.quantity *= split.factor;
-                        position.average_price /= split.factor as f64;
-                    }
-                    StockSplitKind::ReverseSplit => {
-                        position.quantity /= split.factor;
-                        position.average_price *= split.factor as f64;
-                    }
-                },
-            }
+    for (event, day_trade_quantity) in events.iter().zip(day_trades) {
+        position.apply(event, day_trade_quantity)?;
 
-            references.push(position.clone());
-            position.recent_operations.clear();
-        }
+        references.push(position.clone());
+        position.recent_operations.clear();
     }
 
     // Up to here we used the time for the last operation, but we have been asked
@@ -243,19 +413,20 @@ async fn do_calculate_for_symbol(
 }
 
 impl Position {
-    pub fn last(symbol: &str, portfolio_oid: Option<String>) -> Option<Self> {
+    pub fn last(
+        symbol: &str,
+        portfolio_oid: Option<String>,
+        broker_oid: Option<String>,
+    ) -> Option<Self> {
         let db = WalletDB::get_connection();
         let collection = db.collection(Position::collection_name());
 
-        let filter = if let Some(portfolio_oid) = portfolio_oid {
-            doc! {
-                "$and": [
-                    { "symbol": symbol.to_string() },
-                    { "portfolio": portfolio_oid }
-                ]
-            }
-        } else {
-            doc! { "symbol": symbol.to_string() }
+        // Snapshots of each scope are kept apart: global, per portfolio, per broker
+        // and per portfolio and broker.
+        let filter = doc! {
+            "symbol": symbol.to_string(),
+            "portfolio": portfolio_oid.map_or(Bson::Null, Bson::String),
+            "broker": broker_oid.map_or(Bson::Null, Bson::String),
         };
 
         let options = FindOneOptions::builder().sort(doc! { "time": -1 }).build();
@@ -271,9 +442,475 @@ impl Position {
         }
     }
 
+    /// Applies an event to the position. For operations, `day_trade_quantity` is how
+    /// much of it was a day trade, as found by `classify_day_trades`.
+    pub fn apply(&mut self, event: &Event, day_trade_quantity: i64) -> WalletResult<()> {
+        self.time = event.time;
+
+        match &event.detail {
+            EventDetail::StockOperation(StockOperation { operation, .. })
+            | EventDetail::FIIOperation(FIIOperation { operation, .. })
+            | EventDetail::TesouroDiretoOperation(TesouroDiretoOperation { operation, .. }) => {
+                self.apply_lots(event, operation, day_trade_quantity);
+                self.apply_operation(operation, day_trade_quantity);
+            }
+            EventDetail::StockSplit(split) => {
+                let ratio = match split.split_kind {
+                    StockSplitKind::Split => split.factor,
+                    StockSplitKind::ReverseSplit => 1.0 / split.factor,
+                };
+                self.apply_split(self.quantity as f64 * ratio, split.cash_in.unwrap_or(0.0));
+                let (lots, fraction_cost) = scale_lots(&self.lots, ratio, 1.0);
+                if !self.lots.is_empty() {
+                    self.lot_realized += split.cash_in.unwrap_or(0.0) - fraction_cost;
+                }
+                self.lots = lots;
+                self.scale_lent_quantity(ratio);
+            }
+            EventDetail::Bonus(bonus) => {
+                // Bonus shares are not free for tax purposes: they enter the
+                // position at the cost declared by the company.
+                let bonus_quantity = (self.quantity as f64 * bonus.factor).trunc() as i64;
+                self.cost_basis += bonus_quantity as f64 * bonus.cost_per_share;
+                self.quantity += bonus_quantity;
+                self.update_average_price();
+                self.scale_lent_quantity(1.0 + bonus.factor);
+
+                if bonus_quantity > 0 && WalletConfig::lot_ledger() {
+                    self.lots.push(Lot {
+                        id: event.id.clone(),
+                        time: event.time,
+                        quantity: bonus_quantity,
+                        price: bonus.cost_per_share,
+                        cost_basis: bonus_quantity as f64 * bonus.cost_per_share,
+                    });
+                }
+            }
+            EventDetail::TickerChange(_) | EventDetail::Merger(_) | EventDetail::SpinOff(_) => {
+                self.apply_transfer(event)?;
+            }
+            EventDetail::Dividend(income)
+            | EventDetail::JCP(income)
+            | EventDetail::FIIIncome(income) => {
+                self.income += income.net_amount();
+            }
+            EventDetail::LendingStart(lending) => {
+                self.lent_quantity += lending.quantity;
+            }
+            EventDetail::LendingEnd(lending) => {
+                self.lent_quantity = (self.lent_quantity - lending.quantity).max(0);
+            }
+            EventDetail::LendingIncome(income) => {
+                self.lending_income += income.net_amount();
+            }
+            EventDetail::CustodyTransfer(transfer) => {
+                self.apply_custody_transfer(event, transfer)?;
+            }
+        }
+
+        if self.cost_basis != 0.0 {
+            self.yield_on_cost = self.income / self.cost_basis;
+        }
+
+        Ok(())
+    }
+
+    fn apply_operation(&mut self, operation: &BaseOperation, day_trade_quantity: i64) {
+        // Day trades are kept out of the average price, fees are split proportionally.
+        let swing_trade_quantity = operation.quantity - day_trade_quantity;
+        let day_trade_fees =
+            operation.fees.cost() * day_trade_quantity as f64 / operation.quantity as f64;
+        let swing_trade_fees = operation.fees.cost() - day_trade_fees;
+
+        let direction = match operation.kind {
+            OperationKind::Purchase => {
+                self.day_trade_purchases.add(
+                    day_trade_quantity,
+                    operation.price * day_trade_quantity as f64 + day_trade_fees,
+                );
+                1
+            }
+            OperationKind::Sale => {
+                self.day_trade_sales.add(
+                    day_trade_quantity,
+                    operation.price * day_trade_quantity as f64 - day_trade_fees,
+                );
+                -1
+            }
+        };
+
+        if swing_trade_quantity > 0 {
+            self.apply_swing_trade(
+                direction * swing_trade_quantity,
+                operation.price,
+                swing_trade_fees,
+            );
+        }
+
+        // Day trades are realized as soon as both sides of it are there, whichever
+        // came first.
+        let matched = self
+            .day_trade_purchases
+            .quantity
+            .min(self.day_trade_sales.quantity);
+        if matched > 0 {
+            let result =
+                self.day_trade_sales.take(matched) - self.day_trade_purchases.take(matched);
+            self.day_trade_realized += result;
+            self.realized += result;
+        }
+
+        self.fees += operation.fees;
+
+        self.update_average_price();
+
+        self.recent_operations.push(operation.clone());
+    }
+
+    /// Applies a swing trade with a signed quantity: positive for purchases, negative
+    /// for sales. Short positions have negative quantity and cost basis, the latter
+    /// being what was received for the shares, so the average price is the average
+    /// sale price of the short.
+    fn apply_swing_trade(&mut self, quantity: i64, price: f64, fees: f64) {
+        // Whatever goes against the current position closes it first, realizing
+        // the difference to the average price.
+        let closing = if self.quantity.signum() == -quantity.signum() {
+            quantity.abs().min(self.quantity.abs())
+        } else {
+            0
+        };
+
+        if closing > 0 {
+            let closing_fees = fees * closing as f64 / quantity.abs() as f64;
+
+            /* We need to use the average price at the moment of the operation
+             * for the average calculation to work. We may take out too little
+             * if the current price is lower or too much, otherwise.
+             */
+            let cost_price = self.cost_basis / self.quantity as f64;
+            let signed_closing = closing * self.quantity.signum();
+
+            self.cost_basis -= cost_price * signed_closing as f64;
+            self.quantity -= signed_closing;
+
+            self.add_swing_trade_result(
+                signed_closing as f64 * (price - cost_price) - closing_fees,
+            );
+        }
+
+        // The rest opens or increases the position, long or short. Fees add to the
+        // cost of purchases and take from what is received for short sales.
+        let opening = quantity.abs() - closing;
+        if opening > 0 {
+            let opening_fees = fees * opening as f64 / quantity.abs() as f64;
+            let signed_opening = opening * quantity.signum();
+
+            self.cost_basis += price * signed_opening as f64 + opening_fees;
+            self.quantity += signed_opening;
+        }
+
+        if self.quantity == 0 {
+            self.cost_basis = 0.0;
+        }
+    }
+
+    /// Keeps the lot ledger for long holdings: purchases open lots, and sales
+    /// consume them in the order asked for by the operation. Day trades, short
+    /// sales and the purchases covering them stay out of it.
+    fn apply_lots(&mut self, event: &Event, operation: &BaseOperation, day_trade_quantity: i64) {
+        if !WalletConfig::lot_ledger() {
+            return;
+        }
+
+        let swing_trade_quantity = operation.quantity - day_trade_quantity;
+        let fees_per_share = operation.fees.cost() / operation.quantity as f64;
+
+        match operation.kind {
+            OperationKind::Purchase => {
+                let covering = swing_trade_quantity.min(-self.quantity.min(0));
+                let quantity = swing_trade_quantity - covering;
+                if quantity > 0 {
+                    self.lots.push(Lot {
+                        id: event.id.clone(),
+                        time: event.time,
+                        quantity,
+                        price: operation.price,
+                        cost_basis: (operation.price + fees_per_share) * quantity as f64,
+                    });
+                }
+            }
+            OperationKind::Sale => {
+                let mut order = match &operation.lot_matching {
+                    LotMatching::FIFO => (0..self.lots.len()).collect::<Vec<usize>>(),
+                    LotMatching::LIFO => (0..self.lots.len()).rev().collect::<Vec<usize>>(),
+                    LotMatching::Specific(ids) => ids
+                        .iter()
+                        .filter_map(|id| {
+                            self.lots.iter().position(|lot| lot.id.as_ref() == Some(id))
+                        })
+                        .collect::<Vec<usize>>(),
+                };
+                for index in 0..self.lots.len() {
+                    if !order.contains(&index) {
+                        order.push(index);
+                    }
+                }
+
+                let net_price = operation.price - fees_per_share;
+
+                // Whatever goes beyond the long holding opens a short.
+                let mut remaining = swing_trade_quantity.min(self.quantity.max(0));
+                for index in order {
+                    if remaining == 0 {
+                        break;
+                    }
+
+                    let lot = &mut self.lots[index];
+                    let quantity = remaining.min(lot.quantity);
+                    let cost = lot.take(quantity);
+                    self.lot_realized += quantity as f64 * net_price - cost;
+                    remaining -= quantity;
+                }
+
+                // Holdings from before the ledger was kept have no lots, so they
+                // are matched at the average price.
+                self.lot_realized += remaining as f64 * (net_price - self.average_price);
+
+                self.lots.retain(|lot| lot.quantity > 0);
+            }
+        }
+    }
+
+    fn add_swing_trade_result(&mut self, result: f64) {
+        self.swing_trade_realized += result;
+        self.realized += result;
+    }
+
+    /// Only whole shares are kept after a split. B3 auctions off the fractions and
+    /// pays holders in cash, so the cost of the fraction is realized against that.
+    fn apply_split(&mut self, exact_quantity: f64, cash_in: f64) {
+        let quantity = whole_shares(exact_quantity);
+
+        if exact_quantity != 0.0 {
+            let fraction_cost = self.cost_basis * (exact_quantity - quantity) / exact_quantity;
+            self.cost_basis -= fraction_cost;
+            self.add_swing_trade_result(cash_in - fraction_cost);
+        }
+
+        self.quantity = quantity as i64;
+        self.update_average_price();
+    }
+
+    /// Loans are adjusted for splits and bonuses like the shares themselves, so
+    /// that what is lent stays the same part of the holding.
+    fn scale_lent_quantity(&mut self, factor: f64) {
+        let lent_quantity = whole_shares(self.lent_quantity as f64 * factor) as i64;
+        self.lent_quantity = lent_quantity.min(self.quantity.max(0));
+    }
+
+    /// Corporate actions that move holdings between symbols are stored under the
+    /// symbol that gives up the shares, so the receiving side needs to look at what
+    /// the other symbol held right before the event.
+    fn apply_transfer(&mut self, event: &Event) -> WalletResult<()> {
+        if event.symbol == self.symbol {
+            match &event.detail {
+                EventDetail::SpinOff(spin_off) => {
+                    let cost_factor = 1.0 - spin_off.cost_percentage / 100.0;
+                    self.cost_basis *= cost_factor;
+                    self.lots = scale_lots(&self.lots, 1.0, cost_factor).0;
+                    self.update_average_price();
+                }
+                _ => {
+                    self.quantity = 0;
+                    self.cost_basis = 0.0;
+                    self.lent_quantity = 0;
+                    self.lots.clear();
+                }
+            }
+
+            return Ok(());
+        }
+
+        let source = Position::before_event(
+            &event.symbol,
+            self.portfolio.clone(),
+            self.broker.clone(),
+            event,
+        )?;
+        let (quantity_factor, cost_factor, cash_in) = match &event.detail {
+            EventDetail::Merger(merger) => (merger.factor, 1.0, merger.cash_in),
+            EventDetail::SpinOff(spin_off) => (
+                spin_off.factor,
+                spin_off.cost_percentage / 100.0,
+                spin_off.cash_in,
+            ),
+            _ => (1.0, 1.0, None),
+        };
+
+        // Fractions are auctioned off just like for splits, see `apply_split`.
+        let exact_quantity = source.quantity as f64 * quantity_factor;
+        let quantity = whole_shares(exact_quantity);
+        let mut cost_basis = source.cost_basis * cost_factor;
+        if exact_quantity != 0.0 {
+            let fraction_cost = cost_basis * (exact_quantity - quantity) / exact_quantity;
+            cost_basis -= fraction_cost;
+            self.add_swing_trade_result(cash_in.unwrap_or(0.0) - fraction_cost);
+        }
+
+        self.quantity += quantity as i64;
+        self.cost_basis += cost_basis;
+
+        // Loans follow the shares to the new symbol. Spun-off shares are not lent.
+        if let EventDetail::TickerChange(_) | EventDetail::Merger(_) = &event.detail {
+            self.lent_quantity +=
+                whole_shares(source.lent_quantity as f64 * quantity_factor) as i64;
+        }
+        let (lots, fraction_cost) = scale_lots(&source.lots, quantity_factor, cost_factor);
+        if !source.lots.is_empty() {
+            self.lot_realized += cash_in.unwrap_or(0.0) - fraction_cost;
+        }
+        self.lots.extend(lots);
+        self.update_average_price();
+
+        Ok(())
+    }
+
+    /// Custody transfers only matter to the positions of the brokers involved. The
+    /// shares leave the source broker at its average cost, taking the oldest lots.
+    fn apply_custody_transfer(
+        &mut self,
+        event: &Event,
+        transfer: &CustodyTransfer,
+    ) -> WalletResult<()> {
+        let broker = match &self.broker {
+            Some(broker) => broker.clone(),
+            None => return Ok(()),
+        };
+
+        if broker == transfer.from_broker {
+            let (cost, _) = self.take_transfer(transfer.quantity);
+            self.cost_basis -= cost;
+            self.quantity -= transfer.quantity;
+            self.update_average_price();
+        } else if broker == transfer.to_broker {
+            let mut source = Position::before_event(
+                &self.symbol,
+                self.portfolio.clone(),
+                Some(transfer.from_broker.clone()),
+                event,
+            )?;
+
+            let (cost, lots) = source.take_transfer(transfer.quantity);
+            self.cost_basis += cost;
+            self.quantity += transfer.quantity;
+            self.lots.extend(lots);
+            self.update_average_price();
+        }
+
+        Ok(())
+    }
+
+    /// Takes the lots a transfer moves out of this position, along with the cost
+    /// that goes with them. That is the cost of the lots themselves, so that the
+    /// lots keep adding up to the cost basis on both sides, unless the lots do
+    /// not account for all of it, as without the ledger.
+    fn take_transfer(&mut self, quantity: i64) -> (f64, Vec<Lot>) {
+        let lots = take_lots(&mut self.lots, quantity);
+        if lots.iter().map(|lot| lot.quantity).sum::<i64>() == quantity {
+            return (lots.iter().map(|lot| lot.cost_basis).sum(), lots);
+        }
+
+        let cost = if self.quantity != 0 {
+            self.cost_basis * quantity as f64 / self.quantity as f64
+        } else {
+            0.0
+        };
+
+        (cost, lots)
+    }
+
+    /// Replays the events for a symbol up to, but not including, the given event.
+    /// This does not use or create snapshots.
+    fn before_event(
+        symbol: &str,
+        portfolio_oid: Option<String>,
+        broker_oid: Option<String>,
+        event: &Event,
+    ) -> WalletResult<Position> {
+        let mut position = Position::new(symbol, portfolio_oid.clone(), broker_oid.clone());
+
+        let events = get_events_for_symbol(
+            symbol,
+            portfolio_oid,
+            broker_oid,
+            Utc.timestamp(61, 0),
+            event.time,
+        )?;
+        let day_trades = classify_day_trades(&events);
+        for (previous, day_trade_quantity) in events
+            .iter()
+            .zip(day_trades)
+            .take_while(|(e, _)| e.id != event.id)
+        {
+            position.apply(previous, day_trade_quantity)?;
+        }
+
+        Ok(position)
+    }
+
+    /// Calculates the global position at the given time, starting from the last
+    /// snapshot of an earlier day and applying the events that came after that.
+    pub fn calculate_at(symbol: &str, time: DateTime<Utc>) -> WalletResult<Position> {
+        Position::calculate_at_broker(symbol, None, time)
+    }
+
+    /// Same as `calculate_at`, for what is held at the given broker, if any.
+    pub fn calculate_at_broker(
+        symbol: &str,
+        broker_oid: Option<String>,
+        time: DateTime<Utc>,
+    ) -> WalletResult<Position> {
+        // Make sure we do not read snapshots while they are being created.
+        drop(LockMap::lock(Position::collection_name(), symbol));
+
+        let db = WalletDB::get_connection();
+        let collection = db.collection(Position::collection_name());
+
+        // A snapshot from the same day may already hold events after the time.
+        let filter = doc! {
+            "symbol": symbol,
+            "portfolio": Bson::Null,
+            "broker": broker_oid.clone().map_or(Bson::Null, Bson::String),
+            "time": { "$lt": time.date().and_hms(0, 0, 0).to_rfc3339() }
+        };
+
+        let options = FindOneOptions::builder().sort(doc! { "time": -1 }).build();
+
+        let (mut position, date_from) = match collection.find_one(filter, options)? {
+            Some(doc) => {
+                let position = Position::from_doc(doc)?;
+                let date_from = snapshot_replay_start(&position);
+                (position, date_from)
+            }
+            None => (
+                Position::new(symbol, None, broker_oid.clone()),
+                Utc.timestamp(61, 0),
+            ),
+        };
+
+        let events = get_events_for_symbol(symbol, None, broker_oid, date_from, time)?;
+        let day_trades = classify_day_trades(&events);
+        for (event, day_trade_quantity) in events.iter().zip(day_trades) {
+            position.apply(event, day_trade_quantity)?;
+        }
+
+        Ok(position)
+    }
+
     pub fn calculate_for_symbol(
         symbol: &str,
         portfolio_oid: Option<String>,
+        broker_oid: Option<String>,
     ) -> WalletResult<Position> {
         // Ensure we do not try to calculate for the same symbol more than once at a time.
         let _guard = LockMap::lock(Event::collection_name(), symbol);
@@ -285,12 +922,13 @@ impl Position {
 
         let symbol = symbol.to_string();
         let mut position =
-            std::thread::spawn(move || do_calculate_for_symbol(symbol, portfolio_oid))
+            std::thread::spawn(move || do_calculate_for_symbol(symbol, portfolio_oid, broker_oid))
                 .join()
                 .unwrap()?;
 
         // We only care about current price if we still have a position. If not, let's skip this step.
-        if position.quantity > 0 {
+        // Short positions have negative quantity and cost basis, so the gain works out the same.
+        if position.quantity != 0 {
             let current_price = current_price.join().unwrap();
             position.current_price = current_price;
             position.gain = current_price * position.quantity as f64 - position.cost_basis;
@@ -304,18 +942,36 @@ impl Position {
     }
 
     pub fn get_all_for_portfolio(oid: Option<String>) -> WalletResult<Vec<Position>> {
+        Position::get_all(oid, None)
+    }
+
+    pub fn get_all_for_broker(oid: String) -> WalletResult<Vec<Position>> {
+        Position::get_all(None, Some(oid))
+    }
+
+    pub fn get_all(
+        portfolio_oid: Option<String>,
+        broker_oid: Option<String>,
+    ) -> WalletResult<Vec<Position>> {
         let positions = Mutex::new(Vec::<Position>::new());
 
-        let symbols = get_distinct_symbols(oid.clone())?;
+        let symbols = get_distinct_symbols(portfolio_oid.clone(), broker_oid.clone())?;
         symbols
             .into_par_iter()
             .try_for_each::<_, WalletResult<_>>(|symbol| {
-                let position = Position::calculate_for_symbol(&symbol, oid.clone())?;
+                let position = Position::calculate_for_symbol(
+                    &symbol,
+                    portfolio_oid.clone(),
+                    broker_oid.clone(),
+                )?;
 
                 // Old positions will show up here. Maybe we will want to include them
                 // for future views and need a parameter for this function, but for now
                 // just ignore them.
-                if position.quantity > 0 {
+                if position.quantity != 0 {
+                    // Listings leave the ledger to `position_lots`.
+                    let mut position = position;
+                    position.lots.clear();
                     positions.lock().unwrap().push(position);
                 }
 
@@ -343,15 +999,10 @@ impl Position {
         let collection = db.collection(Position::collection_name());
 
         let since = since.unwrap_or_else(|| Utc.ymd(2006, 1, 1).and_hms(0, 0, 0));
-        let filter = if let Some(oid) = oid {
-            doc! {
-                "portfolio": oid,
-                "time": { "$gt": since.to_rfc3339() }
-            }
-        } else {
-            doc! {
-                "time": { "$gt": since.to_rfc3339() }
-            }
+        let filter = doc! {
+            "portfolio": oid.map_or(Bson::Null, Bson::String),
+            "broker": Bson::Null,
+            "time": { "$gt": since.to_rfc3339() }
         };
 
         let options = FindOptions::builder().sort(doc! { "time": 1 });
@@ -376,6 +1027,8 @@ impl Position {
     pub fn create_snapshots(symbol: &str, mut references: Vec<Position>) -> WalletResult<()> {
         info_!("[{}] saving Position snapshots", symbol);
 
+        let source = PriceSource::for_symbol(symbol)?;
+
         let mut previous_position: Option<Position> = None;
         for position in &mut references {
             if let Some(mut previous_position) = previous_position {
@@ -385,17 +1038,14 @@ impl Position {
                     previous_position.time,
                     position.time
                 );
-                for friday in find_all_fridays_between(previous_position.time, position.time) {
-                    let asset_day = Historical::get_for_day_with_fallback(symbol, friday);
+                for date in find_snapshot_dates_between(previous_position.time, position.time) {
+                    let asset_day = Historical::get_for_day_from_source(symbol, &source, date);
                     if let Ok(asset_day) = asset_day {
-                        previous_position.time = friday.and_hms(12, 0, 0);
+                        previous_position.time = date.and_hms(12, 0, 0);
                         previous_position.current_price = asset_day.close;
                     } else {
-                        warn!(
-                            "failed to find historical data for {} on {}",
-                            symbol, friday
-                        );
-                        previous_position.time = friday.and_hms(12, 0, 0);
+                        warn!("failed to find historical data for {} on {}", symbol, date);
+                        previous_position.time = date.and_hms(12, 0, 0);
                     }
 
                     previous_position.gain = previous_position.current_price
@@ -414,10 +1064,10 @@ impl Position {
 
         // Make snapshots come up to yesterday.
         if let Some(mut previous_position) = previous_position {
-            for friday in
-                find_all_fridays_between(previous_position.time, Utc::now() - Duration::days(1))
+            for date in
+                find_snapshot_dates_between(previous_position.time, Utc::now() - Duration::days(1))
             {
-                previous_position.time = friday.and_hms(12, 0, 0);
+                previous_position.time = date.and_hms(12, 0, 0);
                 debug!("[{}] inserting snapshot {:?}", symbol, previous_position);
                 insert_one(previous_position.clone())?;
                 previous_position.recent_operations.clear();
@@ -430,6 +1080,15 @@ impl Position {
     }
 }
 
+/// # List open lots
+///
+/// Lists the lots still held for a symbol, oldest first
+#[openapi]
+#[get("/positions/<symbol>/lots")]
+pub fn position_lots(symbol: String) -> WalletResult<Json<Vec<Lot>>> {
+    Position::calculate_for_symbol(&symbol, None, None).map(|position| Json(position.lots))
+}
+
 #[cfg(test)]
 mod tests {
     use approx::assert_relative_eq;
@@ -437,9 +1096,55 @@ mod tests {
     use std::vec::Vec;
 
     use super::*;
+    use crate::income::Income;
     use crate::operation::{AssetKind, BaseOperation, OperationKind};
     use crate::portfolio::Portfolio;
-    use crate::stock::{StockOperation, StockSplit};
+    use crate::rebalance::TargetAllocation;
+    use crate::stock::{
+        CustodyTransfer, Merger, SpinOff, StockBonus, StockLending, StockOperation, StockSplit,
+        TickerChange,
+    };
+
+    fn operation(
+        symbol: &str,
+        day: u32,
+        kind: OperationKind,
+        price: f64,
+        quantity: i64,
+        broker: Option<&str>,
+    ) -> Event {
+        Event {
+            id: None,
+            symbol: symbol.to_string(),
+            time: Utc.ymd(2020, 1, day).and_hms(12, 0, 0),
+            detail: EventDetail::StockOperation(StockOperation {
+                asset_kind: AssetKind::Stock,
+                operation: BaseOperation {
+                    kind,
+                    broker: broker.map(String::from),
+                    portfolios: Vec::<String>::new(),
+                    price,
+                    quantity,
+                    fees: Fees::default(),
+                    lot_matching: LotMatching::default(),
+                },
+            }),
+        }
+    }
+
+    fn insert_events(events: Vec<Event>) {
+        for event in events {
+            insert_one(event).expect("Failed to insert event");
+        }
+    }
+
+    /// Calculates a position and waits for its snapshots to be saved.
+    fn calculate(symbol: &str, portfolio: Option<String>, broker: Option<String>) -> Position {
+        let position = Position::calculate_for_symbol(symbol, portfolio, broker)
+            .expect("Something went wrong");
+        drop(LockMap::lock(Position::collection_name(), symbol));
+        position
+    }
 
     rusty_fork_test! {
         #[test]
@@ -448,22 +1153,7 @@ mod tests {
 
             let db = WalletDB::get_connection();
 
-            assert!(
-                db.collection(Event::collection_name())
-                    .delete_many(doc! {}, None)
-                    .is_ok(),
-                true
-            );
-
-            assert!(
-                db.collection(Position::collection_name())
-                    .delete_many(doc! {}, None)
-                    .is_ok(),
-                true
-            );
-
             let symbol = String::from("FAKE4");
-
             let default_operation = EventDetail::StockOperation(StockOperation {
                 asset_kind: AssetKind::Stock,
                 operation: BaseOperation {
@@ -472,7 +1162,8 @@ mod tests {
                     portfolios: Vec::<String>::new(),
                     price: 10.0,
                     quantity: 100,
-                    fees: 0.0,
+                    fees: Fees::default(),
+                    lot_matching: LotMatching::default(),
                 },
             });
 
@@ -505,6 +1196,8 @@ mod tests {
             let portfolio = insert_one(Portfolio {
                 id: None,
                 name: "FakePortfolio".to_string(),
+                parent: None,
+                targets: TargetAllocation::default(),
             })
             .expect("Failed to insert Portfolio");
 
@@ -525,7 +1218,8 @@ mod tests {
 
             let split = EventDetail::StockSplit(StockSplit {
                 split_kind: StockSplitKind::Split,
-                factor: 2,
+                factor: 2.0,
+                cash_in: None,
             });
 
             let operation = std::mem::replace(&mut event.detail, split);
@@ -549,16 +1243,30 @@ mod tests {
                 assert!(insert_one(event).is_ok(), true);
             }
 
-            // Do a full update first, which should trigger calculation for our
-            // FAKE4. This means the specific call below should start from an
-            // existing reference.
-            Position::calculate_all().expect("Something went wrong");
+            let dividend = Event {
+                id: None,
+                symbol: symbol.clone(),
+                time: Utc.ymd(2020, 3, 30).and_hms(12, 0, 0),
+                detail: EventDetail::Dividend(Income {
+                    gross_amount: 36.0,
+                    withholding: 6.0,
+                    payment_date: Utc.ymd(2020, 4, 15).and_hms(12, 0, 0),
+                    broker: None,
+                    portfolios: Vec::<String>::new(),
+                }),
+            };
+
+            assert!(insert_one(dividend).is_ok(), true);
+
+            // Do a first calculation, which creates snapshots for FAKE4. This means
+            // the calls below should start from an existing reference.
+            calculate(&symbol, None, None);
 
-            let position = Position::calculate_for_symbol("FAKE4", None);
+            let position = Position::calculate_for_symbol("FAKE4", None, None);
             assert_eq!(position.is_ok(), true);
             let position = position.unwrap();
 
-            let same_position = Position::calculate_for_symbol("FAKE4", None);
+            let same_position = Position::calculate_for_symbol("FAKE4", None, None);
             assert_eq!(same_position.is_ok(), true);
             let same_position = same_position.unwrap();
 
@@ -585,8 +1293,42 @@ mod tests {
                     current_price: 9.0,
                     gain: 1500.0,
                     realized: 100.0,
+                    swing_trade_realized: 100.0,
+                    day_trade_realized: 0.0,
+                    fees: Fees::default(),
+                    income: 30.0,
+                    yield_on_cost: 0.025,
+                    lent_quantity: 0,
+                    lending_income: 0.0,
+                    lots: vec![
+                        Lot {
+                            id: position.lots[0].id.clone(),
+                            time: Utc.ymd(2020, 1, 1).and_hms(12, 0, 0),
+                            quantity: 100,
+                            price: 5.0,
+                            cost_basis: 500.0,
+                        },
+                        Lot {
+                            id: position.lots[1].id.clone(),
+                            time: Utc.ymd(2020, 3, 1).and_hms(12, 0, 0),
+                            quantity: 100,
+                            price: 2.0,
+                            cost_basis: 200.0,
+                        },
+                        Lot {
+                            id: position.lots[2].id.clone(),
+                            time: Utc.ymd(2020, 3, 27).and_hms(12, 0, 0),
+                            quantity: 100,
+                            price: 5.0,
+                            cost_basis: 500.0,
+                        },
+                    ],
+                    lot_realized: 100.0,
                     recent_operations: vec![],
                     portfolio: None,
+                    broker: None,
+                    day_trade_purchases: DayTradeLeg::default(),
+                    day_trade_sales: DayTradeLeg::default(),
                 }
             );
 
@@ -599,6 +1341,8 @@ mod tests {
             // Snapshots should go all the way to "today", so we select a small
             // known sample to verify everything looks ok.
             let filter = doc! {
+                "symbol": "FAKE4",
+                "portfolio": Bson::Null,
                 "time": { "$lt": "2020-04-04" }
             };
 
@@ -636,18 +1380,13 @@ mod tests {
                 assert_relative_eq!(*gain, position.gain);
             }
 
-            let position = Position::calculate_for_symbol("FAKE4", portfolio.id.clone());
-            assert_eq!(position.is_ok(), true);
-
-            // Wait for create_snapshots to finish.
-            let guard = LockMap::lock(Position::collection_name(), "FAKE4");
-            drop(guard);
+            calculate("FAKE4", portfolio.id.clone(), None);
 
             // Make sure snapshots were created for the portfolio as well.
             let filter = doc! {
                 "$and": [
                     { "time": { "$lt": "2020-04-04" } },
-                    { "portfolio": portfolio.id.unwrap() }
+                    { "portfolio": portfolio.id.clone().unwrap() }
                 ]
             };
 
@@ -660,9 +1399,358 @@ mod tests {
             // is from March 1st.
             assert_eq!(positions.len(), 5);
 
-            if let Err(e) = db.drop(None) {
-                println!("Failed to drop test db {}", format!("{:?}", e));
+            WalletDB::drop_database();
+        }
+
+        #[test]
+        fn corporate_actions() {
+            WalletDB::init_client("mongodb://localhost:27017/");
+
+            let event = |symbol: &str, day: u32, detail: EventDetail| Event {
+                id: None,
+                symbol: symbol.to_string(),
+                time: Utc.ymd(2020, 1, day).and_hms(12, 0, 0),
+                detail,
+            };
+
+            insert_events(vec![
+                operation("OLDS3", 6, OperationKind::Purchase, 10.0, 100, None),
+                event(
+                    "OLDS3",
+                    13,
+                    EventDetail::Bonus(StockBonus {
+                        factor: 0.1,
+                        cost_per_share: 5.0,
+                    }),
+                ),
+                event(
+                    "OLDS3",
+                    20,
+                    EventDetail::TickerChange(TickerChange {
+                        new_symbol: "NEWS3".to_string(),
+                    }),
+                ),
+                event(
+                    "NEWS3",
+                    27,
+                    EventDetail::SpinOff(SpinOff {
+                        new_symbol: "SPUN3".to_string(),
+                        factor: 0.5,
+                        cost_percentage: 20.0,
+                        cash_in: None,
+                    }),
+                ),
+                event(
+                    "SPUN3",
+                    28,
+                    EventDetail::StockSplit(StockSplit {
+                        split_kind: StockSplitKind::ReverseSplit,
+                        factor: 10.0,
+                        cash_in: Some(3.0),
+                    }),
+                ),
+                event(
+                    "NEWS3",
+                    29,
+                    EventDetail::Merger(Merger {
+                        new_symbol: "MERG3".to_string(),
+                        factor: 0.25,
+                        cash_in: Some(4.0),
+                    }),
+                ),
+            ]);
+
+            let mut distinct = get_distinct_symbols(None, None).expect("Failed to get symbols");
+            distinct.sort();
+            assert_eq!(distinct, vec!["MERG3", "NEWS3", "OLDS3", "SPUN3"]);
+
+            // The reverse split leaves SPUN3 with 5.5 shares, so half a share
+            // is sold for 3.0 at a cost of 210.0 / 5.5 per share.
+            let split_fraction_cost = 210.0 / 5.5 * 0.5;
+
+            // The merger gives 27.5 MERG3 shares for the 110 NEWS3 ones, so half
+            // a share is sold for 4.0 at a cost of 840.0 / 27.5 per share.
+            let merger_fraction_cost = 840.0 / 27.5 * 0.5;
+
+            // symbol, quantity, cost_basis, average_price, realized
+            let expected = vec![
+                ("OLDS3", 0, 0.0, 0.0, 0.0),
+                ("NEWS3", 0, 0.0, 0.0, 0.0),
+                (
+                    "SPUN3",
+                    5,
+                    210.0 - split_fraction_cost,
+                    (210.0 - split_fraction_cost) / 5.0,
+                    3.0 - split_fraction_cost,
+                ),
+                (
+                    "MERG3",
+                    27,
+                    840.0 - merger_fraction_cost,
+                    (840.0 - merger_fraction_cost) / 27.0,
+                    4.0 - merger_fraction_cost,
+                ),
+            ];
+
+            for (symbol, quantity, cost_basis, average_price, realized) in expected {
+                let position = calculate(symbol, None, None);
+                assert_eq!(position.quantity, quantity);
+                assert_relative_eq!(position.cost_basis, cost_basis);
+                assert_relative_eq!(position.average_price, average_price);
+                assert_relative_eq!(position.realized, realized);
+            }
+
+            WalletDB::drop_database();
+        }
+
+        #[test]
+        fn day_trades() {
+            WalletDB::init_client("mongodb://localhost:27017/");
+
+            let trade = |day: u32, kind: OperationKind, price: f64, broker: &str| {
+                operation("FAKE3", day, kind, price, 100, Some(broker))
+            };
+
+            let mut morning_purchase = trade(10, OperationKind::Purchase, 10.0, "A");
+            morning_purchase.time = Utc.ymd(2020, 1, 10).and_hms(10, 0, 0);
+            let mut afternoon_sale = trade(10, OperationKind::Sale, 11.0, "A");
+            afternoon_sale.time = Utc.ymd(2020, 1, 10).and_hms(15, 0, 0);
+
+            // The sale on the 7th happens before the purchase, and the one on
+            // the 8th is at a different broker, so it is not a day trade. The
+            // 10th is a Friday, so its snapshot is taken between the two trades.
+            let events = vec![
+                trade(6, OperationKind::Purchase, 10.0, "A"),
+                trade(7, OperationKind::Sale, 12.0, "A"),
+                trade(7, OperationKind::Purchase, 11.0, "A"),
+                trade(8, OperationKind::Purchase, 13.0, "A"),
+                trade(8, OperationKind::Sale, 14.0, "B"),
+                morning_purchase,
+                afternoon_sale,
+            ];
+
+            assert_eq!(
+                classify_day_trades(&events),
+                vec![0, 100, 100, 0, 0, 100, 100]
+            );
+
+            insert_events(events);
+
+            // The first calculation creates the snapshots, the others start from them.
+            let positions = vec![
+                calculate("FAKE3", None, None),
+                Position::calculate_at("FAKE3", Utc.ymd(2020, 1, 10).and_hms(23, 0, 0))
+                    .expect("Something went wrong"),
+                calculate("FAKE3", None, None),
+            ];
+
+            for position in positions {
+                assert_eq!(position.quantity, 100);
+                assert_relative_eq!(position.cost_basis, 1150.0);
+                assert_relative_eq!(position.day_trade_realized, 200.0);
+                assert_relative_eq!(position.swing_trade_realized, 250.0);
+                assert_relative_eq!(position.realized, 450.0);
+            }
+
+            WalletDB::drop_database();
+        }
+
+        #[test]
+        fn short_selling() {
+            WalletDB::init_client("mongodb://localhost:27017/");
+
+            // Short 200 shares at an average of 18.0, then cover 150 of them.
+            insert_events(vec![
+                operation("SHRT3", 6, OperationKind::Sale, 20.0, 100, None),
+                operation("SHRT3", 7, OperationKind::Sale, 16.0, 100, None),
+                operation("SHRT3", 8, OperationKind::Purchase, 15.0, 150, None),
+            ]);
+
+            let position = calculate("SHRT3", None, None);
+
+            assert_eq!(position.quantity, -50);
+            assert_relative_eq!(position.cost_basis, -900.0);
+            assert_relative_eq!(position.average_price, 18.0);
+            assert_relative_eq!(position.realized, 450.0);
+
+            // The mocked current price is 9.0, so the short is in profit.
+            assert_relative_eq!(position.gain, 450.0);
+
+            // Shorts and the purchases covering them do not go through lots.
+            assert!(position.lots.is_empty());
+            assert_relative_eq!(position.lot_realized, 0.0);
+
+            WalletDB::drop_database();
+        }
+
+        #[test]
+        fn lending() {
+            WalletDB::init_client("mongodb://localhost:27017/");
+
+            let event = |symbol: &str, day: u32, detail: EventDetail| Event {
+                id: None,
+                symbol: symbol.to_string(),
+                time: Utc.ymd(2020, 1, day).and_hms(12, 0, 0),
+                detail,
+            };
+            let lending = |quantity: i64| StockLending {
+                quantity,
+                broker: None,
+                portfolios: Vec::<String>::new(),
+            };
+
+            insert_events(vec![
+                operation("LEND3", 6, OperationKind::Purchase, 10.0, 100, None),
+                event("LEND3", 7, EventDetail::LendingStart(lending(40))),
+                event(
+                    "LEND3",
+                    8,
+                    EventDetail::StockSplit(StockSplit {
+                        split_kind: StockSplitKind::Split,
+                        factor: 2.0,
+                        cash_in: None,
+                    }),
+                ),
+                event(
+                    "LEND3",
+                    9,
+                    EventDetail::Bonus(StockBonus {
+                        factor: 0.1,
+                        cost_per_share: 5.0,
+                    }),
+                ),
+                event(
+                    "LEND3",
+                    10,
+                    EventDetail::TickerChange(TickerChange {
+                        new_symbol: "LENT3".to_string(),
+                    }),
+                ),
+            ]);
+
+            let position = calculate("LEND3", None, None);
+            assert_eq!(position.quantity, 0);
+            assert_eq!(position.lent_quantity, 0);
+
+            // The loan grows with the split and the bonus, and moves to the new symbol.
+            let position = calculate("LENT3", None, None);
+            assert_eq!(position.quantity, 220);
+            assert_eq!(position.lent_quantity, 88);
+
+            WalletDB::drop_database();
+        }
+
+        #[test]
+        fn lot_ledger() {
+            WalletDB::init_client("mongodb://localhost:27017/");
+
+            let lot_quantity = |position: &Position| -> i64 {
+                position.lots.iter().map(|lot| lot.quantity).sum()
+            };
+
+            let mut day_trade_purchase = operation("LOTS3", 10, OperationKind::Purchase, 100.0, 5, None);
+            day_trade_purchase.time = Utc.ymd(2020, 1, 10).and_hms(10, 0, 0);
+            let mut day_trade_sale = operation("LOTS3", 10, OperationKind::Sale, 110.0, 5, None);
+            day_trade_sale.time = Utc.ymd(2020, 1, 10).and_hms(15, 0, 0);
+
+            // Cover a short of 100 shares, ending up long 50, then buy 25 more
+            // and go through a reverse split that leaves 7.5 shares.
+            insert_events(vec![
+                operation("LOTS3", 6, OperationKind::Sale, 20.0, 100, None),
+                operation("LOTS3", 7, OperationKind::Purchase, 15.0, 150, None),
+                operation("LOTS3", 8, OperationKind::Purchase, 12.0, 25, None),
+                Event {
+                    id: None,
+                    symbol: "LOTS3".to_string(),
+                    time: Utc.ymd(2020, 1, 9).and_hms(12, 0, 0),
+                    detail: EventDetail::StockSplit(StockSplit {
+                        split_kind: StockSplitKind::ReverseSplit,
+                        factor: 10.0,
+                        cash_in: Some(6.0),
+                    }),
+                },
+                day_trade_purchase,
+                day_trade_sale,
+            ]);
+
+            let covered = Position::calculate_at("LOTS3", Utc.ymd(2020, 1, 7).and_hms(23, 0, 0))
+                .expect("Something went wrong");
+            assert_eq!(covered.quantity, 50);
+            assert_eq!(lot_quantity(&covered), covered.quantity);
+            assert_relative_eq!(covered.lots[0].cost_basis, 750.0);
+
+            let split = Position::calculate_at("LOTS3", Utc.ymd(2020, 1, 9).and_hms(23, 0, 0))
+                .expect("Something went wrong");
+            assert_eq!(split.quantity, 7);
+            assert_eq!(lot_quantity(&split), split.quantity);
+
+            // The half share sold in the auction comes from the second lot, which
+            // had 2.5 shares at 120.0 each.
+            assert_relative_eq!(split.lot_realized, 6.0 - 60.0);
+
+            // The day trade does not touch the lots.
+            let position = calculate("LOTS3", None, None);
+            assert_eq!(position.quantity, 7);
+            assert_eq!(position.lots, split.lots);
+            assert_relative_eq!(position.lot_realized, split.lot_realized);
+
+            WalletDB::drop_database();
+        }
+
+        #[test]
+        fn custody_transfer() {
+            WalletDB::init_client("mongodb://localhost:27017/");
+
+            insert_events(vec![
+                operation("XFER3", 6, OperationKind::Purchase, 10.0, 100, Some("A")),
+                operation("XFER3", 7, OperationKind::Purchase, 14.0, 100, Some("A")),
+                Event {
+                    id: None,
+                    symbol: "XFER3".to_string(),
+                    time: Utc.ymd(2020, 1, 8).and_hms(12, 0, 0),
+                    detail: EventDetail::CustodyTransfer(CustodyTransfer {
+                        quantity: 50,
+                        from_broker: "A".to_string(),
+                        to_broker: "B".to_string(),
+                        portfolios: Vec::<String>::new(),
+                    }),
+                },
+                operation("XFER3", 9, OperationKind::Purchase, 20.0, 10, Some("B")),
+            ]);
+
+            // The shares leave A with its oldest lot, and the cost of that lot.
+            let global = calculate("XFER3", None, None);
+            let from = calculate("XFER3", None, Some("A".to_string()));
+            let to = calculate("XFER3", None, Some("B".to_string()));
+
+            assert_eq!(global.quantity, 210);
+            assert_relative_eq!(global.cost_basis, 2600.0);
+            assert_relative_eq!(global.realized, 0.0);
+
+            assert_eq!(from.quantity, 150);
+            assert_relative_eq!(from.cost_basis, 1900.0);
+            assert_eq!(to.quantity, 60);
+            assert_relative_eq!(to.cost_basis, 700.0);
+
+            assert_eq!(from.quantity + to.quantity, global.quantity);
+            assert_relative_eq!(from.cost_basis + to.cost_basis, global.cost_basis);
+
+            assert_eq!(
+                from.lots.iter().map(|lot| lot.quantity).collect::<Vec<i64>>(),
+                vec![50, 100]
+            );
+            assert_eq!(
+                to.lots.iter().map(|lot| lot.quantity).collect::<Vec<i64>>(),
+                vec![50, 10]
+            );
+            assert_eq!(to.lots[0].time, Utc.ymd(2020, 1, 6).and_hms(12, 0, 0));
+
+            for position in &[&from, &to] {
+                let lot_cost = position.lots.iter().map(|lot| lot.cost_basis).sum::<f64>();
+                assert_relative_eq!(lot_cost, position.cost_basis);
             }
+
+            WalletDB::drop_database();
         }
     }
 }
@@ -6,7 +6,9 @@ use std::collections::HashMap;
 use std::sync::Mutex;
 use yahoo_finance::Streamer;
 
+use crate::asset::Asset;
 use crate::event::get_distinct_symbols;
+use crate::tesouro::get_bond_symbols;
 
 struct PriceMap(HashMap<String, f64>);
 impl PriceMap {
@@ -46,20 +48,29 @@ impl PriceCache {
             .expect("Failed to lock price cache map");
     }
 
+    /// Drops the cached price, so that the next lookup goes to the database.
+    pub fn invalidate(symbol: &str) {
+        PRICE_CACHE
+            .lock()
+            .map(|mut price_cache| {
+                price_cache.0.remove(symbol);
+            })
+            .expect("Failed to lock price cache map");
+    }
+
+    /// Streams quotes for the given Yahoo symbols, caching them under the symbols
+    /// they map to.
     #[tokio::main]
-    async fn watch_prices(symbols: Vec<&str>) {
-        let streamer = Streamer::new(symbols);
+    async fn watch_prices(symbols: HashMap<String, String>) {
+        let streamer = Streamer::new(symbols.keys().map(String::as_str).collect());
         loop {
             streamer
                 .stream()
                 .await
                 .for_each(|quote| {
-                    let mut symbol = quote.symbol.to_string();
-
-                    // Remove the .SA.
-                    symbol.truncate(symbol.len() - 3);
-
-                    PriceCache::update_current_price(symbol, quote.price);
+                    if let Some(symbol) = symbols.get(&quote.symbol.to_string()) {
+                        PriceCache::update_current_price(symbol.clone(), quote.price);
+                    }
 
                     future::ready(())
                 })
@@ -77,17 +88,19 @@ impl Fairing for PriceCache {
     }
 
     fn on_launch(&self, _rocket: &Rocket) {
-        let mut symbols = get_distinct_symbols(None).expect("Failed to query mongodb for symbols");
+        let bond_symbols = get_bond_symbols().expect("Failed to query mongodb for bond symbols");
+        let symbols = get_distinct_symbols(None, None)
+            .expect("Failed to query mongodb for symbols")
+            .into_iter()
+            .filter(|symbol| !bond_symbols.contains(symbol))
+            .map(|symbol| {
+                let yahoo_symbol = Asset::yahoo_symbol(&symbol)
+                    .expect("Failed to query mongodb for asset symbols");
+                (yahoo_symbol, symbol)
+            })
+            .collect::<HashMap<String, String>>();
         std::thread::spawn(move || {
-            Self::watch_prices(
-                symbols
-                    .iter_mut()
-                    .map(|s| {
-                        s.push_str(".SA");
-                        String::as_str(s)
-                    })
-                    .collect::<Vec<&str>>(),
-            );
+            Self::watch_prices(symbols);
         });
     }
 }
@@ -0,0 +1,297 @@
+use rocket_contrib::json::Json;
+use rocket_okapi::{openapi, JsonSchema};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::error::WalletResult;
+use crate::event::get_asset_kind;
+use crate::historical::Historical;
+use crate::operation::{AssetKind, OperationKind};
+use crate::portfolio::Portfolio;
+use crate::position::Position;
+use crate::walletdb::get_one;
+
+/// Standard lot for stocks at B3. Smaller quantities go to the fractional market.
+const STANDARD_LOT: i64 = 100;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TargetAllocation {
+    /// Weight of each symbol, as a percentage of the portfolio.
+    #[serde(default)]
+    pub symbols: HashMap<String, f64>,
+
+    /// Weight of each asset kind, as a percentage of the portfolio. What is not
+    /// taken by symbols of that kind with a weight of their own is split equally
+    /// among the others.
+    #[serde(default)]
+    pub asset_kinds: HashMap<AssetKind, f64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RebalanceOrder {
+    pub symbol: String,
+    #[serde(rename = "type")]
+    pub kind: OperationKind,
+    pub quantity: i64,
+    pub price: f64,
+    pub amount: f64,
+    pub current_weight: f64,
+    pub target_weight: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Rebalance {
+    pub orders: Vec<RebalanceOrder>,
+    /// What is left of the contribution after the orders, because of rounding.
+    pub remaining_cash: f64,
+}
+
+#[derive(Clone, Debug)]
+struct Holding {
+    kind: Option<AssetKind>,
+    price: f64,
+    value: f64,
+    target_weight: f64,
+}
+
+fn assign_target_weights(holdings: &mut BTreeMap<String, Holding>, targets: &TargetAllocation) {
+    for (symbol, holding) in holdings.iter_mut() {
+        holding.target_weight = targets.symbols.get(symbol).cloned().unwrap_or(0.0);
+    }
+
+    for (kind, weight) in &targets.asset_kinds {
+        let (weighted, others): (Vec<_>, Vec<_>) = holdings
+            .iter_mut()
+            .filter(|(_, holding)| holding.kind.as_ref() == Some(kind))
+            .partition(|(symbol, _)| targets.symbols.contains_key(*symbol));
+
+        if others.is_empty() {
+            continue;
+        }
+
+        let taken = weighted
+            .iter()
+            .map(|(_, holding)| holding.target_weight)
+            .sum::<f64>();
+        let share = ((weight - taken) / others.len() as f64).max(0.0);
+        for (_, holding) in others {
+            holding.target_weight = share;
+        }
+    }
+}
+
+fn suggest_orders(
+    holdings: &BTreeMap<String, Holding>,
+    contribution: f64,
+    sell: bool,
+    fractional: bool,
+) -> Rebalance {
+    // Holdings we have no price for cannot be valued nor traded, so they are left
+    // out of the totals altogether. Missing prices may come as NaN.
+    let priced = holdings
+        .iter()
+        .filter(|(_, holding)| holding.price.is_finite() && holding.price > 0.0)
+        .collect::<Vec<(&String, &Holding)>>();
+
+    let current_total = priced.iter().map(|(_, holding)| holding.value).sum::<f64>();
+    let total = current_total + contribution;
+
+    // How far each holding is from its target, in money.
+    let mut deltas = priced
+        .into_iter()
+        .map(|(symbol, holding)| {
+            let delta = total * holding.target_weight / 100.0 - holding.value;
+            (symbol, if sell { delta } else { delta.max(0.0) })
+        })
+        .filter(|(_, delta)| *delta != 0.0)
+        .collect::<Vec<(&String, f64)>>();
+
+    // Without selling only the contribution is available, so it is split among the
+    // holdings below target in proportion to how far below they are.
+    if !sell {
+        let shortfall = deltas.iter().map(|(_, delta)| delta).sum::<f64>();
+        if shortfall > contribution {
+            for (_, delta) in deltas.iter_mut() {
+                *delta *= contribution / shortfall;
+            }
+        }
+    }
+
+    // Sales come first, so that they pay for the purchases. Purchases go from
+    // the furthest below target down, in case rounding leaves some of them out.
+    deltas.sort_by(|(_, a), (_, b)| {
+        (*a >= 0.0)
+            .cmp(&(*b >= 0.0))
+            .then_with(|| Position::float_cmp(&b.abs(), &a.abs()))
+    });
+
+    let mut remaining_cash = contribution;
+    let mut orders = Vec::<RebalanceOrder>::new();
+    for (symbol, delta) in deltas {
+        let holding = &holdings[symbol];
+        let lot = if fractional || holding.kind != Some(AssetKind::Stock) {
+            1
+        } else {
+            STANDARD_LOT
+        };
+
+        let mut amount = delta.abs();
+        if delta > 0.0 {
+            amount = amount.min(remaining_cash);
+        }
+
+        let quantity = (amount / holding.price) as i64 / lot * lot;
+        if quantity == 0 {
+            continue;
+        }
+
+        let amount = quantity as f64 * holding.price;
+        let kind = if delta > 0.0 {
+            remaining_cash -= amount;
+            OperationKind::Purchase
+        } else {
+            remaining_cash += amount;
+            OperationKind::Sale
+        };
+
+        orders.push(RebalanceOrder {
+            symbol: symbol.clone(),
+            kind,
+            quantity,
+            price: holding.price,
+            amount,
+            current_weight: if current_total != 0.0 {
+                100.0 * holding.value / current_total
+            } else {
+                0.0
+            },
+            target_weight: holding.target_weight,
+        });
+    }
+
+    Rebalance {
+        orders,
+        remaining_cash,
+    }
+}
+
+/// # Suggest orders to rebalance a portfolio
+///
+/// Compares the current value of each position with the portfolio's target
+/// allocation and returns the orders that bring it closest to the targets, using
+/// the given contribution. Sales are only suggested if `sell` is true, and stocks
+/// are bought in standard lots unless `fractional` is true
+#[openapi]
+#[get("/portfolios/<oid>/rebalance?<contribution>&<sell>&<fractional>")]
+pub fn portfolio_rebalance(
+    oid: String,
+    contribution: Option<f64>,
+    sell: Option<bool>,
+    fractional: Option<bool>,
+) -> WalletResult<Json<Rebalance>> {
+    let portfolio = get_one::<Portfolio>(oid.clone())?;
+
+    let mut holdings = BTreeMap::<String, Holding>::new();
+    for position in Position::get_all_for_portfolio(Some(oid))? {
+        holdings.insert(
+            position.symbol.clone(),
+            Holding {
+                kind: None,
+                price: position.current_price,
+                value: position.current_price * position.quantity as f64,
+                target_weight: 0.0,
+            },
+        );
+    }
+
+    // Symbols we want but do not hold yet.
+    for symbol in portfolio.targets.symbols.keys() {
+        if !holdings.contains_key(symbol) {
+            holdings.insert(
+                symbol.clone(),
+                Holding {
+                    kind: None,
+                    price: Historical::current_price_for_symbol(symbol.clone()),
+                    value: 0.0,
+                    target_weight: 0.0,
+                },
+            );
+        }
+    }
+
+    for (symbol, holding) in holdings.iter_mut() {
+        holding.kind = get_asset_kind(symbol)?;
+    }
+
+    assign_target_weights(&mut holdings, &portfolio.targets);
+
+    Ok(Json(suggest_orders(
+        &holdings,
+        contribution.unwrap_or(0.0),
+        sell.unwrap_or(false),
+        fractional.unwrap_or(false),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn rebalance_orders() {
+        let holding = |kind: AssetKind, price: f64, quantity: i64| Holding {
+            kind: Some(kind),
+            price,
+            value: price * quantity as f64,
+            target_weight: 0.0,
+        };
+
+        let mut holdings = BTreeMap::<String, Holding>::new();
+        holdings.insert("AAAA3".to_string(), holding(AssetKind::Stock, 10.0, 500));
+        holdings.insert("BBBB3".to_string(), holding(AssetKind::Stock, 10.0, 100));
+        holdings.insert("CCCC11".to_string(), holding(AssetKind::FII, 100.0, 30));
+
+        // AAAA3 gets 30% of its own, BBBB3 is left with the other 30% for stocks.
+        let mut targets = TargetAllocation::default();
+        targets.symbols.insert("AAAA3".to_string(), 30.0);
+        targets.asset_kinds.insert(AssetKind::Stock, 60.0);
+        targets.asset_kinds.insert(AssetKind::FII, 40.0);
+
+        assign_target_weights(&mut holdings, &targets);
+        assert_relative_eq!(holdings["AAAA3"].target_weight, 30.0);
+        assert_relative_eq!(holdings["BBBB3"].target_weight, 30.0);
+        assert_relative_eq!(holdings["CCCC11"].target_weight, 40.0);
+
+        // With 10,000 the total is 19,000: BBBB3 is 4,700 below its target,
+        // CCCC11 is 4,600 below and AAAA3 only 700, less than a standard lot.
+        let rebalance = suggest_orders(&holdings, 10_000.0, false, false);
+        assert_eq!(rebalance.orders.len(), 2);
+        assert_eq!(rebalance.orders[0].symbol, "BBBB3");
+        assert_eq!(rebalance.orders[0].quantity, 400);
+        assert_eq!(rebalance.orders[1].symbol, "CCCC11");
+        assert_eq!(rebalance.orders[1].quantity, 46);
+        assert_relative_eq!(rebalance.remaining_cash, 1_400.0);
+
+        // Selling AAAA3 frees money for the others.
+        let rebalance = suggest_orders(&holdings, 0.0, true, true);
+        assert_eq!(rebalance.orders[0].symbol, "AAAA3");
+        assert_eq!(rebalance.orders[0].kind, OperationKind::Sale);
+        assert_eq!(rebalance.orders[0].quantity, 230);
+
+        // A holding without a price is left out, without spoiling the others.
+        holdings.insert(
+            "DDDD3".to_string(),
+            holding(AssetKind::Stock, f64::NAN, 100),
+        );
+        let rebalance = suggest_orders(&holdings, 10_000.0, false, false);
+        assert_eq!(rebalance.orders.len(), 2);
+        assert_eq!(rebalance.orders[0].symbol, "BBBB3");
+        assert_eq!(rebalance.orders[0].quantity, 400);
+        assert_relative_eq!(rebalance.remaining_cash, 1_400.0);
+    }
+}
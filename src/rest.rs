@@ -1,8 +1,9 @@
-use mongodb::bson::{doc, oid, to_bson, Bson};
+use chrono::{NaiveDate, TimeZone, Utc};
+use mongodb::bson::{doc, oid, to_bson, Bson, Document};
 use mongodb::options::FindOptions;
 use okapi::openapi3::Responses;
-use rocket::http::Status;
-use rocket::request::{Form, Request};
+use rocket::http::{RawStr, Status};
+use rocket::request::{Form, FromFormValue, Request};
 use rocket::response::{Responder, Response};
 use rocket_contrib::json::Json;
 use rocket_okapi::gen::OpenApiGenerator;
@@ -43,6 +44,101 @@ pub struct ListingOptions {
     pub _end: Option<i64>,
     pub _order: Option<String>,
     pub _sort: Option<String>,
+
+    pub symbol: Option<String>,
+
+    /// Only what happened on this date or later.
+    pub time_gte: Option<DateParam>,
+
+    /// Only what happened on this date or earlier.
+    pub time_lte: Option<DateParam>,
+
+    /// Only events of this type.
+    #[form(field = "eventType")]
+    #[serde(rename = "eventType")]
+    pub event_type: Option<String>,
+
+    /// Only events involving this broker, including custody transfers.
+    #[form(field = "detail.broker")]
+    #[serde(rename = "detail.broker")]
+    pub broker: Option<String>,
+
+    /// Only events that belong to this portfolio, or to any nested under it.
+    #[form(field = "detail.portfolios")]
+    #[serde(rename = "detail.portfolios")]
+    pub portfolio: Option<String>,
+
+    /// Free text, searched for in the fields each kind of object chooses.
+    pub q: Option<String>,
+}
+
+impl ListingOptions {
+    /// Builds the mongodb filter for the options every kind of object has,
+    /// searching for `q` in any of `search_fields`.
+    pub fn filter(&self, search_fields: &[&str]) -> WalletResult<Document> {
+        let mut filter = Document::new();
+
+        if let Some(symbol) = &self.symbol {
+            filter.insert("symbol", symbol.clone());
+        }
+
+        let mut time = Document::new();
+        if let Some(from) = self.time_gte {
+            time.insert(
+                "$gte",
+                Utc.from_utc_date(&from.0).and_hms(0, 0, 0).to_rfc3339(),
+            );
+        }
+        if let Some(until) = self.time_lte {
+            time.insert(
+                "$lte",
+                Utc.from_utc_date(&until.0).and_hms(23, 59, 59).to_rfc3339(),
+            );
+        }
+        if !time.is_empty() {
+            filter.insert("time", time);
+        }
+
+        if let Some(q) = self.q.as_ref().filter(|_| !search_fields.is_empty()) {
+            let pattern = escape_regex(q);
+            let matches = search_fields
+                .iter()
+                .map(|field| {
+                    let mut matches = Document::new();
+                    matches.insert(*field, doc! { "$regex": pattern.clone(), "$options": "i" });
+                    Bson::Document(matches)
+                })
+                .collect::<Vec<Bson>>();
+            filter.insert("$or", matches);
+        }
+
+        Ok(filter)
+    }
+}
+
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// A date in a query string, like `2020-12-31`.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct DateParam(pub NaiveDate);
+
+impl<'v> FromFormValue<'v> for DateParam {
+    type Error = &'v RawStr;
+
+    fn from_form_value(value: &'v RawStr) -> Result<Self, Self::Error> {
+        NaiveDate::parse_from_str(value.as_str(), "%Y-%m-%d")
+            .map(DateParam)
+            .map_err(|_| value)
+    }
 }
 
 pub fn api_add<T>(operation: Json<T>) -> WalletResult<Json<T>>
@@ -59,7 +155,8 @@ pub fn api_get<T>(
 where
     T: Queryable,
 {
-    let filter = id.map(|id| {
+    let mut filter = Document::new();
+    if let Some(id) = id {
         // This is just string to Bson. It shouldn't really fail unless something went
         // quite wrong, so we just panic if it fails to convert.
         let ids_to_lookup = id
@@ -67,13 +164,18 @@ where
             .map(|s| Bson::ObjectId(oid::ObjectId::with_string(s).unwrap()))
             .collect::<Vec<Bson>>();
 
-        doc! {
-            "_id": { "$in": to_bson(&Bson::Array(ids_to_lookup)).unwrap() }
-        }
-    });
+        filter.insert(
+            "_id",
+            doc! { "$in": to_bson(&Bson::Array(ids_to_lookup)).unwrap() },
+        );
+    }
 
     let mut find_options: Option<FindOptions> = None;
     if let Some(options) = options {
+        for (key, value) in T::listing_filter(&options)? {
+            filter.insert(key, value);
+        }
+
         let skip = options._start;
         let limit = {
             if options._end.is_some() && options._start.is_some() {
@@ -113,8 +215,8 @@ where
         );
     };
 
-    let count = get_count::<T>()?;
-    get::<T>(filter, find_options).map(|results| Rest(Json(results), count as usize))
+    let count = get_count::<T>(filter.clone())?;
+    get::<T>(Some(filter), find_options).map(|results| Rest(Json(results), count as usize))
 }
 
 pub fn api_get_one<T>(oid: String) -> WalletResult<Json<T>>
@@ -137,3 +239,40 @@ where
 {
     delete_one::<T>(oid).map(Json)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listing_filter() {
+        let options = ListingOptions {
+            symbol: Some("PETR4".to_string()),
+            time_gte: Some(DateParam(NaiveDate::from_ymd(2020, 1, 1))),
+            event_type: Some("stock-operation".to_string()),
+            broker: Some("xp".to_string()),
+            q: Some("b3.sa".to_string()),
+            ..Default::default()
+        };
+
+        let filter = options.filter(&["symbol", "name"]).unwrap();
+        assert_eq!(filter.get_str("symbol").unwrap(), "PETR4");
+        // Filters that only make sense for events are left to them.
+        assert!(!filter.contains_key("eventType"));
+        assert!(!filter.contains_key("detail.broker"));
+        assert_eq!(
+            filter.get_document("time").unwrap(),
+            &doc! { "$gte": "2020-01-01T00:00:00+00:00" }
+        );
+
+        let search = filter.get_array("$or").unwrap();
+        assert_eq!(search.len(), 2);
+        assert_eq!(
+            search[1],
+            Bson::Document(doc! { "name": { "$regex": "b3\\.sa", "$options": "i" } })
+        );
+
+        // Without fields to search in, free text is ignored.
+        assert!(!options.filter(&[]).unwrap().contains_key("$or"));
+    }
+}
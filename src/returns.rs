@@ -0,0 +1,248 @@
+use chrono::{DateTime, TimeZone, Utc};
+use rocket_contrib::json::Json;
+use rocket_okapi::{openapi, JsonSchema};
+use serde::{Deserialize, Serialize};
+
+use crate::error::WalletResult;
+use crate::event::{get_distinct_symbols, get_events_for_symbol, EventDetail};
+use crate::fii::FIIOperation;
+use crate::operation::{BaseOperation, OperationKind};
+use crate::position::{classify_day_trades, Position};
+use crate::stock::StockOperation;
+use crate::tesouro::TesouroDiretoOperation;
+
+type CashFlow = (DateTime<Utc>, f64);
+
+/// Money-weighted return, from the cash flows of purchases, sales and income,
+/// with what is still held valued at the current price.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MoneyWeightedReturn {
+    pub invested: f64,
+    pub received: f64,
+    pub current_value: f64,
+    /// Internal rate of return (XIRR), per year.
+    pub annualized: Option<f64>,
+    /// The annualized return compounded over the period since the first cash flow.
+    pub cumulative: Option<f64>,
+    /// Symbols still held that have no current price. There is no return while
+    /// there are any, as what they are worth is unknown.
+    pub unpriced: Vec<String>,
+}
+
+impl MoneyWeightedReturn {
+    fn from_cash_flows(
+        mut flows: Vec<CashFlow>,
+        current_value: f64,
+        unpriced: Vec<String>,
+    ) -> Self {
+        let now = Utc::now();
+        flows.sort_by_key(|(time, _)| *time);
+
+        let invested = -flows.iter().map(|(_, a)| a.min(0.0)).sum::<f64>();
+        let received = flows.iter().map(|(_, a)| a.max(0.0)).sum::<f64>();
+
+        let first = flows.first().map(|(time, _)| *time);
+        flows.push((now, current_value));
+
+        let annualized = if unpriced.is_empty() {
+            xirr(&flows)
+        } else {
+            None
+        };
+        let cumulative = match (annualized, first) {
+            (Some(rate), Some(first)) => Some((1.0 + rate).powf(years_between(first, now)) - 1.0),
+            _ => None,
+        };
+
+        MoneyWeightedReturn {
+            invested,
+            received,
+            current_value,
+            annualized,
+            cumulative,
+            unpriced,
+        }
+    }
+}
+
+fn years_between(from: DateTime<Utc>, to: DateTime<Utc>) -> f64 {
+    (to - from).num_seconds() as f64 / (365.0 * 24.0 * 60.0 * 60.0)
+}
+
+/// Finds the annual rate that brings the present value of the cash flows to zero.
+/// Returns `None` if there is no such rate, e.g. when all flows have the same sign.
+pub fn xirr(flows: &[CashFlow]) -> Option<f64> {
+    let start = flows.iter().map(|(time, _)| *time).min()?;
+    let npv = |rate: f64| {
+        flows
+            .iter()
+            .map(|(time, amount)| amount / (1.0 + rate).powf(years_between(start, *time)))
+            .sum::<f64>()
+    };
+
+    // Bisection is slower than Newton's method, but does not wander off with
+    // unusual cash flows. Look for a bracket first, losses can't go beyond -100%.
+    let mut low = -0.999_999;
+    let mut high = 1.0;
+    while npv(low).signum() == npv(high).signum() {
+        high *= 2.0;
+        if high > 1e6 {
+            return None;
+        }
+    }
+
+    for _ in 0..200 {
+        let middle = (low + high) / 2.0;
+        if npv(middle).signum() == npv(low).signum() {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+
+    Some((low + high) / 2.0)
+}
+
+fn operation_cash_flow(operation: &BaseOperation) -> f64 {
+    let amount = operation.price * operation.quantity as f64;
+    match operation.kind {
+        OperationKind::Purchase => -(amount + operation.fees.cost()),
+        OperationKind::Sale => amount - operation.fees.cost(),
+    }
+}
+
+fn cash_flows_for_symbol(
+    symbol: &str,
+    portfolio_oid: Option<String>,
+) -> WalletResult<Vec<CashFlow>> {
+    let events = get_events_for_symbol(
+        symbol,
+        portfolio_oid.clone(),
+        None,
+        Utc.timestamp(61, 0),
+        Utc::now(),
+    )?;
+    let day_trades = classify_day_trades(&events);
+
+    let mut flows = Vec::<CashFlow>::new();
+    let mut position = Position::new(symbol, portfolio_oid, None);
+    for (event, day_trade_quantity) in events.iter().zip(day_trades) {
+        let cost_basis = position.cost_basis;
+        position.apply(event, day_trade_quantity)?;
+
+        let amount = match &event.detail {
+            EventDetail::StockOperation(StockOperation { operation, .. })
+            | EventDetail::FIIOperation(FIIOperation { operation, .. })
+            | EventDetail::TesouroDiretoOperation(TesouroDiretoOperation { operation, .. }) => {
+                operation_cash_flow(operation)
+            }
+            EventDetail::StockSplit(split) => split.cash_in.unwrap_or(0.0),
+            EventDetail::Dividend(income)
+            | EventDetail::JCP(income)
+            | EventDetail::FIIIncome(income)
+            | EventDetail::LendingIncome(income) => income.net_amount(),
+            // Holdings moving between symbols are taken as bought by the receiving
+            // symbol and sold by the other at cost, so they cancel out in portfolios.
+            EventDetail::TickerChange(_) | EventDetail::Merger(_) | EventDetail::SpinOff(_) => {
+                cost_basis - position.cost_basis
+            }
+            _ => 0.0,
+        };
+
+        if amount != 0.0 {
+            flows.push((event.time, amount));
+        }
+    }
+
+    Ok(flows)
+}
+
+/// What is still held is worth, or None if it has no price.
+fn current_value(position: &Position) -> Option<f64> {
+    if position.quantity == 0 {
+        Some(0.0)
+    } else if position.current_price.is_finite() {
+        Some(position.current_price * position.quantity as f64)
+    } else {
+        None
+    }
+}
+
+/// # Obtain the money-weighted return of a position
+///
+/// Returns the XIRR of a symbol's cash flows, annualized and cumulative
+#[openapi]
+#[get("/positions/<symbol>/xirr")]
+pub fn position_xirr(symbol: String) -> WalletResult<Json<MoneyWeightedReturn>> {
+    let position = Position::calculate_for_symbol(&symbol, None, None)?;
+    let flows = cash_flows_for_symbol(&symbol, None)?;
+
+    let (value, unpriced) = match current_value(&position) {
+        Some(value) => (value, vec![]),
+        None => (0.0, vec![symbol]),
+    };
+
+    Ok(Json(MoneyWeightedReturn::from_cash_flows(
+        flows, value, unpriced,
+    )))
+}
+
+/// # Obtain the money-weighted return of a portfolio
+///
+/// Returns the XIRR of the cash flows of all positions in a portfolio, or in all
+/// portfolios if none is given, annualized and cumulative
+#[openapi]
+#[get("/portfolios/xirr?<oid>")]
+pub fn portfolio_xirr(oid: Option<String>) -> WalletResult<Json<MoneyWeightedReturn>> {
+    let mut flows = Vec::<CashFlow>::new();
+    let mut value = 0.0;
+    let mut unpriced = Vec::<String>::new();
+    for symbol in get_distinct_symbols(oid.clone(), None)? {
+        let position = Position::calculate_for_symbol(&symbol, oid.clone(), None)?;
+        flows.extend(cash_flows_for_symbol(&symbol, oid.clone())?);
+        match current_value(&position) {
+            Some(current_value) => value += current_value,
+            None => unpriced.push(symbol),
+        }
+    }
+
+    Ok(Json(MoneyWeightedReturn::from_cash_flows(
+        flows, value, unpriced,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn internal_rate_of_return() {
+        let flows = vec![
+            (Utc.ymd(2019, 1, 1).and_hms(0, 0, 0), -1000.0),
+            (Utc.ymd(2020, 1, 1).and_hms(0, 0, 0), 1100.0),
+        ];
+        assert_relative_eq!(xirr(&flows).unwrap(), 0.1, epsilon = 1e-9);
+
+        // A second contribution half way through, at a lower price.
+        let flows = vec![
+            (Utc.ymd(2019, 1, 1).and_hms(0, 0, 0), -1000.0),
+            (Utc.ymd(2019, 7, 2).and_hms(12, 0, 0), -1000.0),
+            (Utc.ymd(2020, 1, 1).and_hms(0, 0, 0), 2200.0),
+        ];
+        let rate = xirr(&flows).unwrap();
+        assert!(rate > 0.1 && rate < 0.15);
+
+        // Nothing ever came back.
+        let flows = vec![(Utc.ymd(2019, 1, 1).and_hms(0, 0, 0), -1000.0)];
+        assert_eq!(xirr(&flows), None);
+
+        // Without a price for what is held, there is no telling the return.
+        let result = MoneyWeightedReturn::from_cash_flows(flows, 0.0, vec!["NOPR3".to_string()]);
+        assert_eq!(result.annualized, None);
+        assert_eq!(result.cumulative, None);
+        assert_eq!(result.unpriced, vec!["NOPR3".to_string()]);
+    }
+}
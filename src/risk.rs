@@ -0,0 +1,225 @@
+use chrono::{Date, NaiveDate, Utc};
+use rocket_contrib::json::Json;
+use rocket_okapi::{openapi, JsonSchema};
+use serde::{Deserialize, Serialize};
+
+use crate::benchmark::benchmark_series;
+use crate::error::WalletResult;
+use crate::portfolio::reference_series;
+use crate::rest::DateParam;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Drawdown {
+    /// The fall from the peak, as a fraction of it.
+    pub value: f64,
+    pub start: NaiveDate,
+    pub trough: NaiveDate,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RiskMetrics {
+    /// Standard deviation of the returns between snapshots, annualized.
+    pub volatility: Option<f64>,
+    /// Largest fall from a previous peak, if the portfolio ever fell.
+    pub max_drawdown: Option<Drawdown>,
+    /// Annualized excess return over CDI, divided by its annualized volatility,
+    /// if CDI rates were imported for the period.
+    pub sharpe_ratio: Option<f64>,
+    /// Sensitivity of the returns to those of IBOV, if its prices are known.
+    pub beta: Option<f64>,
+}
+
+fn returns(values: &[f64]) -> Vec<f64> {
+    values.windows(2).map(|w| w[1] / w[0] - 1.0).collect()
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// Sample covariance. Needs at least two values.
+fn covariance(a: &[f64], b: &[f64]) -> f64 {
+    let (mean_a, mean_b) = (mean(a), mean(b));
+    a.iter()
+        .zip(b)
+        .map(|(x, y)| (x - mean_a) * (y - mean_b))
+        .sum::<f64>()
+        / (a.len() - 1) as f64
+}
+
+fn max_drawdown(series: &[(Date<Utc>, f64)]) -> Option<Drawdown> {
+    let mut peak = series.first()?;
+    let mut worst: Option<Drawdown> = None;
+    for point in series {
+        if point.1 > peak.1 {
+            peak = point;
+            continue;
+        }
+
+        let value = 1.0 - point.1 / peak.1;
+        if value > 0.0 && worst.as_ref().map_or(true, |worst| value > worst.value) {
+            worst = Some(Drawdown {
+                value,
+                start: peak.0.naive_utc(),
+                trough: point.0.naive_utc(),
+            });
+        }
+    }
+
+    worst
+}
+
+/// Computes the metrics for a series of portfolio values, given CDI and IBOV
+/// series for the same dates, if there is data for them.
+fn risk_metrics(
+    series: &[(Date<Utc>, f64)],
+    cdi: Option<&[f64]>,
+    ibov: Option<&[f64]>,
+) -> RiskMetrics {
+    let max_drawdown = max_drawdown(series);
+
+    let values = series.iter().map(|(_, value)| *value).collect::<Vec<f64>>();
+    let portfolio = returns(&values);
+    let days = match (series.first(), series.last()) {
+        (Some(first), Some(last)) => (last.0 - first.0).num_days(),
+        _ => 0,
+    };
+
+    if portfolio.len() < 2 || days == 0 {
+        return RiskMetrics {
+            volatility: None,
+            max_drawdown,
+            sharpe_ratio: None,
+            beta: None,
+        };
+    }
+
+    // Snapshots may be daily, weekly or monthly, so the number of periods in
+    // a year comes from the dates themselves.
+    let periods_per_year = 365.25 * portfolio.len() as f64 / days as f64;
+
+    let deviation = covariance(&portfolio, &portfolio).sqrt();
+    let volatility = deviation * periods_per_year.sqrt();
+
+    // Without CDI rates the risk free return is unknown, rather than zero.
+    let sharpe_ratio = cdi.and_then(|cdi| {
+        let excess = portfolio
+            .iter()
+            .zip(returns(cdi))
+            .map(|(r, risk_free)| r - risk_free)
+            .collect::<Vec<f64>>();
+        let excess_deviation = covariance(&excess, &excess).sqrt();
+        if excess_deviation > 0.0 {
+            Some(mean(&excess) / excess_deviation * periods_per_year.sqrt())
+        } else {
+            None
+        }
+    });
+
+    let beta = ibov.and_then(|ibov| {
+        let market = returns(ibov);
+        let market_variance = covariance(&market, &market);
+        if market.len() == portfolio.len() && market_variance > 0.0 {
+            Some(covariance(&portfolio, &market) / market_variance)
+        } else {
+            None
+        }
+    });
+
+    RiskMetrics {
+        volatility: Some(volatility),
+        max_drawdown,
+        sharpe_ratio,
+        beta,
+    }
+}
+
+/// # Obtain risk metrics
+///
+/// Returns the volatility, maximum drawdown, Sharpe ratio against CDI and beta
+/// against IBOV of a portfolio, or of all portfolios if none is given, computed
+/// from the position snapshots between `from` and `to`
+#[openapi]
+#[get("/portfolios/risk?<oid>&<from>&<to>")]
+pub fn portfolio_risk(
+    oid: Option<String>,
+    from: Option<DateParam>,
+    to: Option<DateParam>,
+) -> WalletResult<Json<RiskMetrics>> {
+    let series = reference_series(oid)?
+        .into_iter()
+        .filter(|(date, _)| from.map_or(true, |from| date.naive_utc() >= from.0))
+        .filter(|(date, _)| to.map_or(true, |to| date.naive_utc() <= to.0))
+        .collect::<Vec<(Date<Utc>, f64)>>();
+
+    let dates = series
+        .iter()
+        .map(|(date, _)| *date)
+        .collect::<Vec<Date<Utc>>>();
+    let cdi = benchmark_series("CDI", &dates)?;
+    let ibov = benchmark_series("IBOV", &dates)?;
+
+    Ok(Json(risk_metrics(&series, cdi.as_deref(), ibov.as_deref())))
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn portfolio_risk_metrics() {
+        let series = vec![
+            (Utc.ymd(2020, 6, 5), 100.0),
+            (Utc.ymd(2020, 6, 12), 110.0),
+            (Utc.ymd(2020, 6, 19), 99.0),
+            (Utc.ymd(2020, 6, 26), 103.95),
+        ];
+        let values = series.iter().map(|(_, v)| *v).collect::<Vec<f64>>();
+        let flat = vec![100.0; 4];
+
+        let metrics = risk_metrics(&series, Some(&flat[..]), Some(&values[..]));
+
+        let drawdown = metrics.max_drawdown.unwrap();
+        assert_relative_eq!(drawdown.value, 0.1, epsilon = 1e-9);
+        assert_eq!(drawdown.start, NaiveDate::from_ymd(2020, 6, 12));
+        assert_eq!(drawdown.trough, NaiveDate::from_ymd(2020, 6, 19));
+
+        // Returns of 10%, -10% and 5%, weekly.
+        let average = 0.05 / 3.0;
+        let deviation =
+            (((0.1_f64 - average).powi(2) + (-0.1 - average).powi(2) + (0.05 - average).powi(2))
+                / 2.0)
+                .sqrt();
+        let periods_per_year = 365.25 / 7.0;
+        assert_relative_eq!(
+            metrics.volatility.unwrap(),
+            deviation * periods_per_year.sqrt(),
+            epsilon = 1e-9
+        );
+        assert_relative_eq!(
+            metrics.sharpe_ratio.unwrap(),
+            average / deviation * periods_per_year.sqrt(),
+            epsilon = 1e-9
+        );
+
+        // The portfolio follows the market exactly.
+        assert_relative_eq!(metrics.beta.unwrap(), 1.0, epsilon = 1e-9);
+
+        // Without a market that moves there is no beta.
+        assert_eq!(
+            risk_metrics(&series, Some(&flat[..]), Some(&flat[..])).beta,
+            None
+        );
+
+        // Nor without any market or CDI data at all.
+        let metrics = risk_metrics(&series, None, None);
+        assert!(metrics.volatility.is_some());
+        assert_eq!(metrics.sharpe_ratio, None);
+        assert_eq!(metrics.beta, None);
+    }
+}
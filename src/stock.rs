@@ -35,7 +35,85 @@ fn split_kind() -> StockSplitKind {
 pub struct StockSplit {
     #[serde(default = "split_kind", rename = "splitType")]
     pub split_kind: StockSplitKind,
-    pub factor: i64,
+
+    /// May be fractional, e.g. 1.5 for a 3:2 split.
+    pub factor: f64,
+
+    /// Cash received for the fractions of shares B3 auctioned off.
+    #[serde(default, rename = "cashIn")]
+    pub cash_in: Option<f64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StockBonus {
+    /// New shares received for each share held, e.g. 0.1 for a 10% bonus.
+    pub factor: f64,
+
+    /// Cost the company attributes to each new share.
+    pub cost_per_share: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TickerChange {
+    pub new_symbol: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Merger {
+    pub new_symbol: String,
+
+    /// Shares of the new symbol received for each share held.
+    pub factor: f64,
+
+    /// Cash received for the fractions of shares B3 auctioned off.
+    #[serde(default)]
+    pub cash_in: Option<f64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SpinOff {
+    pub new_symbol: String,
+
+    /// Shares of the new symbol received for each share held.
+    pub factor: f64,
+
+    /// Percentage of the original cost basis that moves to the new symbol.
+    pub cost_percentage: f64,
+
+    /// Cash received for the fractions of shares B3 auctioned off.
+    #[serde(default)]
+    pub cash_in: Option<f64>,
+}
+
+/// Shares lent out through the B3 BTC (Banco de Títulos) system. They are still
+/// owned while lent, but cannot be sold until the lending ends.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub struct StockLending {
+    pub quantity: i64,
+
+    pub broker: Option<String>,
+
+    #[serde(default = "Vec::<String>::new")]
+    pub portfolios: Vec<String>,
+}
+
+/// Shares moved from one broker's custody to another's. Cost and acquisition
+/// dates stay the same, only broker positions change.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CustodyTransfer {
+    pub quantity: i64,
+
+    pub from_broker: String,
+
+    pub to_broker: String,
+
+    #[serde(default = "Vec::<String>::new")]
+    pub portfolios: Vec<String>,
 }
 
 /// # Get a stock position
@@ -44,5 +122,5 @@ pub struct StockSplit {
 #[openapi]
 #[get("/stocks/position/<symbol>")]
 pub fn get_stock_position_by_symbol(symbol: String) -> WalletResult<Json<Position>> {
-    Position::calculate_for_symbol(&symbol, None).map(Json)
+    Position::calculate_for_symbol(&symbol, None, None).map(Json)
 }
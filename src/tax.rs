@@ -0,0 +1,570 @@
+use chrono::{DateTime, Datelike, Duration, TimeZone, Utc};
+use mongodb::bson::doc;
+use rocket_contrib::json::Json;
+use rocket_okapi::{openapi, JsonSchema};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::asset::Asset;
+use crate::error::{BackendError, WalletResult};
+use crate::event::{
+    get_asset_kind, get_distinct_symbols, get_events_for_symbol, Event, EventDetail,
+};
+use crate::fii::FIIOperation;
+use crate::operation::{AssetKind, BaseOperation, OperationKind};
+use crate::position::{classify_day_trades, Position};
+use crate::stock::{Merger, SpinOff, StockOperation};
+use crate::walletdb::{Queryable, WalletDB};
+
+/// Monthly stock sales up to this amount have their swing trade gains exempted.
+const SWING_TRADE_EXEMPTION: f64 = 20_000.0;
+
+/// DARFs below this amount are not paid, but added to the next month's.
+const DARF_MINIMUM: f64 = 10.0;
+
+/// Receita Federal code for capital gains on the stock exchange.
+const DARF_CODE: &str = "6015";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TaxCategory {
+    SwingTrade,
+    DayTrade,
+    FII,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CategoryTax {
+    pub sales: f64,
+    pub result: f64,
+    pub exempt: bool,
+    pub loss_offset: f64,
+    pub taxable: f64,
+    pub rate: f64,
+    pub tax: f64,
+    pub carried_loss: f64,
+}
+
+impl CategoryTax {
+    fn settle(&mut self, carried_loss: &mut f64, rate: f64, exemption: Option<f64>) {
+        if self.result < 0.0 {
+            *carried_loss -= self.result;
+        } else if exemption.map_or(false, |exemption| self.sales <= exemption) {
+            self.exempt = true;
+        } else {
+            self.loss_offset = carried_loss.min(self.result);
+            *carried_loss -= self.loss_offset;
+            self.taxable = self.result - self.loss_offset;
+            self.tax = self.taxable * rate;
+        }
+
+        self.rate = rate;
+        self.carried_loss = *carried_loss;
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MonthlyTax {
+    pub year: i32,
+    pub month: u32,
+    pub swing_trade: CategoryTax,
+    pub day_trade: CategoryTax,
+    pub fii: CategoryTax,
+    pub irrf: f64,
+    pub tax: f64,
+    pub due: f64,
+    pub darf: f64,
+    pub darf_code: String,
+}
+
+impl MonthlyTax {
+    fn new(year: i32, month: u32) -> Self {
+        MonthlyTax {
+            year,
+            month,
+            darf_code: DARF_CODE.to_string(),
+            ..MonthlyTax::default()
+        }
+    }
+
+    fn category_mut(&mut self, category: TaxCategory) -> &mut CategoryTax {
+        match category {
+            TaxCategory::SwingTrade => &mut self.swing_trade,
+            TaxCategory::DayTrade => &mut self.day_trade,
+            TaxCategory::FII => &mut self.fii,
+        }
+    }
+}
+
+/// Values that carry over from one month to the next.
+#[derive(Debug, Default)]
+struct Carried {
+    swing_trade_loss: f64,
+    day_trade_loss: f64,
+    fii_loss: f64,
+    irrf: f64,
+    darf: f64,
+}
+
+fn settle_months(months: &mut [MonthlyTax]) {
+    let mut carried = Carried::default();
+
+    for month in months {
+        month.swing_trade.settle(
+            &mut carried.swing_trade_loss,
+            0.15,
+            Some(SWING_TRADE_EXEMPTION),
+        );
+        month
+            .day_trade
+            .settle(&mut carried.day_trade_loss, 0.2, None);
+        month.fii.settle(&mut carried.fii_loss, 0.2, None);
+
+        month.tax = month.swing_trade.tax + month.day_trade.tax + month.fii.tax;
+
+        let irrf = month.irrf + carried.irrf;
+        month.due = (month.tax - irrf).max(0.0);
+        carried.irrf = (irrf - month.tax).max(0.0);
+
+        let darf = month.due + carried.darf;
+        if darf < DARF_MINIMUM {
+            carried.darf = darf;
+        } else {
+            carried.darf = 0.0;
+            month.darf = darf;
+        }
+    }
+}
+
+/// Returns the swing trade and day trade sale amounts, plus the IRRF withheld.
+fn sale_amounts(operation: &BaseOperation, day_trade_quantity: i64) -> (f64, f64, f64) {
+    match operation.kind {
+        OperationKind::Sale => (
+            operation.price * (operation.quantity - day_trade_quantity) as f64,
+            operation.price * day_trade_quantity as f64,
+            operation.fees.irrf,
+        ),
+        OperationKind::Purchase => (0.0, 0.0, 0.0),
+    }
+}
+
+/// Categories for the swing trade and day trade parts of sales of a kind of asset.
+fn categories_for(kind: Option<AssetKind>) -> (TaxCategory, TaxCategory) {
+    match kind {
+        // FII day trades are taxed the same as other FII sales.
+        Some(AssetKind::FII) => (TaxCategory::FII, TaxCategory::FII),
+        _ => (TaxCategory::SwingTrade, TaxCategory::DayTrade),
+    }
+}
+
+fn collect_results(
+    symbol: &str,
+    until: DateTime<Utc>,
+    months: &mut BTreeMap<(i32, u32), MonthlyTax>,
+) -> WalletResult<()> {
+    let mut position = Position::new(symbol, None, None);
+
+    let events = get_events_for_symbol(symbol, None, None, Utc.timestamp(61, 0), until)?;
+    let day_trades = classify_day_trades(&events);
+
+    for (event, day_trade_quantity) in events.iter().zip(day_trades) {
+        let swing_trade_realized = position.swing_trade_realized;
+        let day_trade_realized = position.day_trade_realized;
+        position.apply(event, day_trade_quantity)?;
+
+        let (categories, (swing_trade_sales, day_trade_sales, irrf)) = match &event.detail {
+            EventDetail::StockOperation(StockOperation { operation, .. }) => (
+                categories_for(Some(AssetKind::Stock)),
+                sale_amounts(operation, day_trade_quantity),
+            ),
+            EventDetail::FIIOperation(FIIOperation { operation, .. }) => (
+                categories_for(Some(AssetKind::FII)),
+                sale_amounts(operation, day_trade_quantity),
+            ),
+            // Fractions auctioned off after a split are sales of the symbol as well.
+            EventDetail::StockSplit(split) => (
+                categories_for(get_asset_kind(symbol)?),
+                (split.cash_in.unwrap_or(0.0), 0.0, 0.0),
+            ),
+            // So are the fractions of shares received in mergers and spin-offs,
+            // which are realized on the receiving symbol.
+            EventDetail::Merger(Merger { cash_in, .. })
+            | EventDetail::SpinOff(SpinOff { cash_in, .. })
+                if event.symbol != symbol =>
+            {
+                (
+                    categories_for(get_asset_kind(symbol)?),
+                    (cash_in.unwrap_or(0.0), 0.0, 0.0),
+                )
+            }
+            // Tesouro Direto is taxed at source, and the other events do not
+            // realize any results.
+            _ => continue,
+        };
+
+        let swing_trade_result = position.swing_trade_realized - swing_trade_realized;
+        let day_trade_result = position.day_trade_realized - day_trade_realized;
+        if swing_trade_sales + day_trade_sales == 0.0
+            && swing_trade_result == 0.0
+            && day_trade_result == 0.0
+        {
+            continue;
+        }
+
+        let (year, month) = (event.time.year(), event.time.month());
+        let month_tax = months
+            .entry((year, month))
+            .or_insert_with(|| MonthlyTax::new(year, month));
+
+        month_tax.irrf += irrf;
+
+        let totals = month_tax.category_mut(categories.0);
+        totals.sales += swing_trade_sales;
+        totals.result += swing_trade_result;
+
+        let totals = month_tax.category_mut(categories.1);
+        totals.sales += day_trade_sales;
+        totals.result += day_trade_result;
+    }
+
+    Ok(())
+}
+
+/// Calculates taxes for every month with sales up to the given one, which is
+/// always included. All of them are needed, as losses, IRRF and small DARFs
+/// carry over from one month to the next.
+pub fn calculate_monthly_taxes(year: i32, month: u32) -> WalletResult<Vec<MonthlyTax>> {
+    Utc.ymd_opt(year, month, 1)
+        .single()
+        .ok_or_else(|| BackendError::BadRequest(format!("Invalid month: {}/{}", month, year)))?;
+
+    let next_month = if month == 12 {
+        Utc.ymd(year + 1, 1, 1)
+    } else {
+        Utc.ymd(year, month + 1, 1)
+    };
+    let until = next_month.and_hms(0, 0, 0) - Duration::seconds(1);
+
+    let mut months = BTreeMap::<(i32, u32), MonthlyTax>::new();
+    months.insert((year, month), MonthlyTax::new(year, month));
+
+    for symbol in get_distinct_symbols(None, None)? {
+        collect_results(&symbol, until, &mut months)?;
+    }
+
+    let mut months = months.into_iter().map(|(_, tax)| tax).collect::<Vec<_>>();
+    settle_months(&mut months);
+
+    Ok(months)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Holding {
+    pub symbol: String,
+    /// Issuer CNPJ, when known.
+    pub cnpj: Option<String>,
+    pub quantity: i64,
+    pub average_price: f64,
+    pub cost_basis: f64,
+}
+
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema,
+)]
+pub enum IncomeKind {
+    #[serde(rename = "dividend")]
+    Dividend,
+    #[serde(rename = "jcp")]
+    JCP,
+    #[serde(rename = "fii-income")]
+    FIIIncome,
+    #[serde(rename = "lending-income")]
+    LendingIncome,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct IncomeEntry {
+    pub symbol: String,
+    /// Issuer CNPJ, when known.
+    pub cnpj: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: IncomeKind,
+    pub gross_amount: f64,
+    pub withholding: f64,
+    pub net_amount: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AnnualReport {
+    pub year: i32,
+    pub holdings: Vec<Holding>,
+    pub exempt_income: Vec<IncomeEntry>,
+    pub taxable_income: Vec<IncomeEntry>,
+    pub months: Vec<MonthlyTax>,
+}
+
+fn collect_holdings(year: i32) -> WalletResult<Vec<Holding>> {
+    let end_of_year = Utc.ymd(year, 12, 31).and_hms(23, 59, 59);
+
+    let mut holdings = Vec::<Holding>::new();
+    for symbol in get_distinct_symbols(None, None)? {
+        let position = Position::calculate_at(&symbol, end_of_year)?;
+        if position.quantity > 0 {
+            let cnpj = Asset::find(&symbol)?.and_then(|asset| asset.cnpj);
+            holdings.push(Holding {
+                symbol,
+                cnpj,
+                quantity: position.quantity,
+                average_price: position.average_price,
+                cost_basis: position.cost_basis,
+            });
+        }
+    }
+
+    holdings.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+
+    Ok(holdings)
+}
+
+/// Income is declared for the year it was paid in, so this goes by payment date.
+fn collect_income(year: i32) -> WalletResult<Vec<IncomeEntry>> {
+    let db = WalletDB::get_connection();
+    let collection = db.collection(Event::collection_name());
+
+    let filter = doc! {
+        "eventType": { "$in": ["dividend", "jcp", "fii-income", "lending-income"] },
+        "detail.paymentDate": {
+            "$gte": Utc.ymd(year, 1, 1).and_hms(0, 0, 0).to_rfc3339(),
+            "$lte": Utc.ymd(year, 12, 31).and_hms(23, 59, 59).to_rfc3339()
+        }
+    };
+
+    let mut entries = BTreeMap::<(String, IncomeKind), IncomeEntry>::new();
+    for event in Event::from_docs(collection.find(filter, None)?)? {
+        let (kind, income) = match &event.detail {
+            EventDetail::Dividend(income) => (IncomeKind::Dividend, income),
+            EventDetail::JCP(income) => (IncomeKind::JCP, income),
+            EventDetail::FIIIncome(income) => (IncomeKind::FIIIncome, income),
+            EventDetail::LendingIncome(income) => (IncomeKind::LendingIncome, income),
+            _ => continue,
+        };
+
+        let entry = entries
+            .entry((event.symbol.clone(), kind))
+            .or_insert_with(|| IncomeEntry {
+                symbol: event.symbol.clone(),
+                cnpj: None,
+                kind,
+                gross_amount: 0.0,
+                withholding: 0.0,
+                net_amount: 0.0,
+            });
+
+        if entry.cnpj.is_none() {
+            entry.cnpj = Asset::find(&event.symbol)?.and_then(|asset| asset.cnpj);
+        }
+        entry.gross_amount += income.gross_amount;
+        entry.withholding += income.withholding;
+        entry.net_amount += income.net_amount();
+    }
+
+    Ok(entries.into_iter().map(|(_, entry)| entry).collect())
+}
+
+/// # Calculate the monthly capital gains tax
+///
+/// Returns results and tax due for swing trades, day trades and FIIs on the
+/// given month, with the DARF amount to be paid
+#[openapi]
+#[get("/tax/monthly?<year>&<month>")]
+pub fn monthly_tax(year: i32, month: u32) -> WalletResult<Json<MonthlyTax>> {
+    let months = calculate_monthly_taxes(year, month)?;
+    months
+        .into_iter()
+        .find(|tax| tax.year == year && tax.month == month)
+        .map(Json)
+        .ok_or(BackendError::NotFound)
+}
+
+/// # Obtain the annual income tax report
+///
+/// Returns what is needed for the IRPF declaration of a year: holdings on December
+/// 31st, exempt and taxable income, and the monthly swing trade and day trade results
+#[openapi]
+#[get("/tax/annual?<year>")]
+pub fn annual_tax_report(year: i32) -> WalletResult<Json<AnnualReport>> {
+    let months = calculate_monthly_taxes(year, 12)?
+        .into_iter()
+        .filter(|tax| tax.year == year)
+        .collect::<Vec<MonthlyTax>>();
+
+    // Dividends and FII income are exempt, while JCP and lending fees are taxed at source.
+    let (taxable_income, exempt_income) = collect_income(year)?
+        .into_iter()
+        .partition(|entry| matches!(entry.kind, IncomeKind::JCP | IncomeKind::LendingIncome));
+
+    Ok(Json(AnnualReport {
+        year,
+        holdings: collect_holdings(year)?,
+        exempt_income,
+        taxable_income,
+        months,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use mongodb::bson::Bson;
+    use rusty_fork::rusty_fork_test;
+
+    use super::*;
+    use crate::income::Income;
+    use crate::operation::{AssetKind, Fees, LotMatching};
+    use crate::walletdb::insert_one;
+
+    #[test]
+    fn monthly_settlement() {
+        let mut months = (1..=4)
+            .map(|m| MonthlyTax::new(2020, m))
+            .collect::<Vec<_>>();
+
+        // A loss, carried over to the following months.
+        months[0].swing_trade.sales = 30_000.0;
+        months[0].swing_trade.result = -1_000.0;
+
+        // Gains above the exemption limit get the loss deducted. FII gains are
+        // never exempt, but the tax is too small for a DARF.
+        months[1].swing_trade.sales = 25_000.0;
+        months[1].swing_trade.result = 3_000.0;
+        months[1].irrf = 1.25;
+        months[2].fii.sales = 1_000.0;
+        months[2].fii.result = 40.0;
+
+        // Exempt swing trade gain, and the FII tax is added to the one carried.
+        months[3].swing_trade.sales = 10_000.0;
+        months[3].swing_trade.result = 500.0;
+        months[3].fii.sales = 1_000.0;
+        months[3].fii.result = 40.0;
+
+        settle_months(&mut months);
+
+        assert_relative_eq!(months[0].swing_trade.carried_loss, 1_000.0);
+        assert_relative_eq!(months[0].darf, 0.0);
+
+        assert_relative_eq!(months[1].swing_trade.loss_offset, 1_000.0);
+        assert_relative_eq!(months[1].swing_trade.taxable, 2_000.0);
+        assert_relative_eq!(months[1].tax, 300.0);
+        assert_relative_eq!(months[1].darf, 298.75);
+
+        assert_relative_eq!(months[2].tax, 8.0);
+        assert_relative_eq!(months[2].darf, 0.0);
+
+        assert!(months[3].swing_trade.exempt);
+        assert_relative_eq!(months[3].tax, 8.0);
+        assert_relative_eq!(months[3].darf, 16.0);
+        assert_eq!(months[3].darf_code, "6015");
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn annual_report() {
+            WalletDB::init_client("mongodb://localhost:27017/");
+
+            let db = WalletDB::get_connection();
+
+            insert_one(Asset {
+                id: None,
+                symbol: "TAXS3".to_string(),
+                name: "Tax Test S.A.".to_string(),
+                cnpj: Some("00.000.000/0001-91".to_string()),
+                isin: None,
+                asset_kind: AssetKind::Stock,
+                sector: None,
+                segment: None,
+                yahoo_symbol: None,
+            })
+            .expect("Failed to insert asset");
+
+            let operation = |time: DateTime<Utc>, kind: OperationKind, price: f64| Event {
+                id: None,
+                symbol: "TAXS3".to_string(),
+                time,
+                detail: EventDetail::StockOperation(StockOperation {
+                    asset_kind: AssetKind::Stock,
+                    operation: BaseOperation {
+                        kind,
+                        broker: None,
+                        portfolios: vec![],
+                        price,
+                        quantity: 100,
+                        fees: Fees::default(),
+                        lot_matching: LotMatching::default(),
+                    },
+                }),
+            };
+
+            let jcp = Event {
+                id: None,
+                symbol: "TAXS3".to_string(),
+                time: Utc.ymd(2020, 6, 30).and_hms(12, 0, 0),
+                detail: EventDetail::JCP(Income {
+                    gross_amount: 20.0,
+                    withholding: 3.0,
+                    payment_date: Utc.ymd(2020, 8, 14).and_hms(12, 0, 0),
+                    broker: None,
+                    portfolios: vec![],
+                }),
+            };
+
+            // The purchase in 2021 is not held at the end of 2020.
+            let events = vec![
+                operation(Utc.ymd(2020, 3, 2).and_hms(12, 0, 0), OperationKind::Purchase, 10.0),
+                operation(Utc.ymd(2020, 3, 3).and_hms(12, 0, 0), OperationKind::Purchase, 12.0),
+                operation(Utc.ymd(2020, 6, 1).and_hms(12, 0, 0), OperationKind::Sale, 13.0),
+                operation(Utc.ymd(2021, 2, 1).and_hms(12, 0, 0), OperationKind::Purchase, 15.0),
+                jcp,
+            ];
+            for event in events {
+                insert_one(event).expect("Failed to insert event");
+            }
+
+            let report = annual_tax_report(2020).expect("Failed to get report").into_inner();
+
+            let holding = report
+                .holdings
+                .iter()
+                .find(|holding| holding.symbol == "TAXS3")
+                .expect("Holding not found");
+            assert_eq!(holding.quantity, 100);
+            assert_relative_eq!(holding.cost_basis, 1100.0);
+            assert_relative_eq!(holding.average_price, 11.0);
+            assert_eq!(holding.cnpj.as_deref(), Some("00.000.000/0001-91"));
+
+            assert!(report.exempt_income.is_empty());
+            assert_eq!(report.taxable_income.len(), 1);
+            assert_eq!(report.taxable_income[0].kind, IncomeKind::JCP);
+            assert_relative_eq!(report.taxable_income[0].net_amount, 17.0);
+
+            // Sales below the exemption limit in June, and December is always there.
+            assert_eq!(report.months.len(), 2);
+            assert_eq!(report.months[0].month, 6);
+            assert!(report.months[0].swing_trade.exempt);
+            assert_relative_eq!(report.months[0].swing_trade.result, 200.0);
+            assert_eq!(report.months[1].month, 12);
+
+            // Reports only read, they do not create snapshots.
+            let snapshots = db
+                .collection(Position::collection_name())
+                .count_documents(doc! { "symbol": "TAXS3", "broker": Bson::Null }, None)
+                .expect("Failed to count snapshots");
+            assert_eq!(snapshots, 0);
+
+            WalletDB::drop_database();
+        }
+    }
+}
@@ -0,0 +1,397 @@
+use chrono::{Date, DateTime, Duration, Utc};
+use mongodb::bson::{doc, to_bson};
+use mongodb::options::{FindOneOptions, UpdateOptions};
+use rocket::request::Form;
+use rocket_contrib::json::Json;
+use rocket_okapi::{openapi, JsonSchema};
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{BackendError, WalletResult};
+use crate::event::{bson_to_strings, Event, EventDetail};
+use crate::operation::{AssetKind, BaseOperation, Fees, LotMatching, OperationKind};
+use crate::position::Position;
+use crate::price_cache::PriceCache;
+use crate::rest::*;
+use crate::walletdb::{Queryable, WalletDB};
+
+fn asset_kind() -> AssetKind {
+    AssetKind::TesouroDireto
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub enum BondKind {
+    #[serde(rename = "selic")]
+    Selic,
+    #[serde(rename = "prefixado")]
+    Prefixado,
+    #[serde(rename = "prefixado-juros")]
+    PrefixadoJuros,
+    #[serde(rename = "ipca")]
+    IPCA,
+    #[serde(rename = "ipca-juros")]
+    IPCAJuros,
+    #[serde(rename = "igpm-juros")]
+    IGPMJuros,
+}
+
+/// Tesouro Direto sells bonds in fractions of down to 0.01, while positions only
+/// hold whole quantities. Bond operations and prices are kept in hundredths of a
+/// bond instead.
+pub const BOND_FRACTIONS: f64 = 100.0;
+
+/// A Tesouro Direto purchase or sale. The API takes the number of bonds and the
+/// bond's unit price (PU) on the day, the operation holds them in hundredths.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(from = "BondOperationInput", into = "BondOperationInput")]
+pub struct TesouroDiretoOperation {
+    pub asset_kind: AssetKind,
+
+    pub bond_type: BondKind,
+
+    pub maturity: DateTime<Utc>,
+
+    pub operation: BaseOperation,
+}
+
+/// What the API and database see of a Tesouro Direto operation.
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+struct BondOperationInput {
+    #[serde(rename = "assetType", default = "asset_kind")]
+    asset_kind: AssetKind,
+
+    bond_type: BondKind,
+
+    maturity: DateTime<Utc>,
+
+    /// Unit price (PU) of the bond.
+    price: f64,
+
+    /// Number of bonds, in steps of 0.01.
+    quantity: f64,
+
+    #[serde(default)]
+    fees: Fees,
+
+    #[serde(rename = "type")]
+    kind: OperationKind,
+
+    broker: Option<String>,
+
+    #[serde(default = "Vec::<String>::new")]
+    portfolios: Vec<String>,
+
+    /// Only meaningful for sales.
+    #[serde(default)]
+    lot_matching: LotMatching,
+}
+
+impl From<BondOperationInput> for TesouroDiretoOperation {
+    fn from(input: BondOperationInput) -> TesouroDiretoOperation {
+        TesouroDiretoOperation {
+            asset_kind: input.asset_kind,
+            bond_type: input.bond_type,
+            maturity: input.maturity,
+            operation: BaseOperation {
+                price: input.price / BOND_FRACTIONS,
+                quantity: (input.quantity * BOND_FRACTIONS).round() as i64,
+                fees: input.fees,
+                kind: input.kind,
+                broker: input.broker,
+                portfolios: input.portfolios,
+                lot_matching: input.lot_matching,
+            },
+        }
+    }
+}
+
+impl From<TesouroDiretoOperation> for BondOperationInput {
+    fn from(bond: TesouroDiretoOperation) -> BondOperationInput {
+        let operation = bond.operation;
+        BondOperationInput {
+            asset_kind: bond.asset_kind,
+            bond_type: bond.bond_type,
+            maturity: bond.maturity,
+            price: operation.price * BOND_FRACTIONS,
+            quantity: operation.quantity as f64 / BOND_FRACTIONS,
+            fees: operation.fees,
+            kind: operation.kind,
+            broker: operation.broker,
+            portfolios: operation.portfolios,
+            lot_matching: operation.lot_matching,
+        }
+    }
+}
+
+impl JsonSchema for TesouroDiretoOperation {
+    fn schema_name() -> String {
+        "TesouroDiretoOperation".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        BondOperationInput::json_schema(gen)
+    }
+}
+
+/// Unit price (PU) of a bond on a given day, as published by Tesouro Nacional.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BondPrice {
+    #[serde(alias = "_id")]
+    pub id: Option<String>,
+    pub bond_type: BondKind,
+    pub maturity: DateTime<Utc>,
+    pub time: DateTime<Utc>,
+    pub unit_price: f64,
+}
+
+impl Queryable for BondPrice {
+    fn collection_name() -> &'static str {
+        "bond-prices"
+    }
+}
+
+impl BondPrice {
+    pub fn get_for_day_with_fallback(
+        bond: &TesouroDiretoOperation,
+        date: Date<Utc>,
+    ) -> WalletResult<BondPrice> {
+        let db = WalletDB::get_connection();
+        let collection = db.collection(BondPrice::collection_name());
+
+        // Same as with historical data, look back a week to get through
+        // weekends and holidays.
+        let range_from = (date - Duration::days(7)).and_hms(0, 0, 0).to_rfc3339();
+        let range_to = date.and_hms(23, 59, 59).to_rfc3339();
+
+        let filter = doc! {
+            "$and": [
+                { "bondType": to_bson(&bond.bond_type)? },
+                { "maturity": to_bson(&bond.maturity)? },
+                { "time": { "$gte": range_from } },
+                { "time": { "$lte": range_to } },
+            ]
+        };
+
+        let find_options = FindOneOptions::builder().sort(doc! { "time": -1 });
+
+        collection
+            .find_one(filter, find_options.build())?
+            .map_or(Err(BackendError::NotFound), BondPrice::from_doc)
+    }
+}
+
+/// Finds the bond a symbol refers to, if it is used for Tesouro Direto operations.
+pub fn get_bond_for_symbol(symbol: &str) -> WalletResult<Option<TesouroDiretoOperation>> {
+    let db = WalletDB::get_connection();
+    let collection = db.collection(Event::collection_name());
+
+    let filter = doc! {
+        "symbol": symbol,
+        "eventType": "tesouro-direto-operation"
+    };
+
+    if let Some(document) = collection.find_one(filter, None)? {
+        if let EventDetail::TesouroDiretoOperation(bond) = Event::from_doc(document)?.detail {
+            return Ok(Some(bond));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Lists the symbols used for Tesouro Direto operations. These are priced from
+/// the local bond price table, not from Yahoo.
+pub fn get_bond_symbols() -> WalletResult<Vec<String>> {
+    let db = WalletDB::get_connection();
+    let collection = db.collection(Event::collection_name());
+
+    let filter = doc! {
+        "eventType": "tesouro-direto-operation"
+    };
+
+    bson_to_strings(collection.distinct("symbol", filter, None)?)
+}
+
+/// Lists the symbols used for operations on the given bond.
+fn get_symbols_for_bond(
+    bond_type: &BondKind,
+    maturity: &DateTime<Utc>,
+) -> WalletResult<Vec<String>> {
+    let db = WalletDB::get_connection();
+    let collection = db.collection(Event::collection_name());
+
+    let filter = doc! {
+        "eventType": "tesouro-direto-operation",
+        "detail.bondType": to_bson(bond_type)?,
+        "detail.maturity": to_bson(maturity)?,
+    };
+
+    bson_to_strings(collection.distinct("symbol", filter, None)?)
+}
+
+/// # Import bond prices
+///
+/// Adds unit prices (PU) for Tesouro Direto bonds, used to value positions. Prices
+/// already imported for the same bond and day are replaced
+#[openapi]
+#[post("/tesouro/prices", data = "<prices>")]
+pub fn import_bond_prices(prices: Json<Vec<BondPrice>>) -> WalletResult<()> {
+    let db = WalletDB::get_connection();
+    let collection = db.collection(BondPrice::collection_name());
+
+    let mut bonds = Vec::<(BondKind, DateTime<Utc>)>::new();
+    for price in prices.into_inner() {
+        let mut doc = price.to_doc()?;
+        doc.remove("_id");
+
+        let bond = (price.bond_type.clone(), price.maturity);
+        if !bonds.contains(&bond) {
+            bonds.push(bond);
+        }
+
+        let filter = doc! {
+            "bondType": to_bson(&price.bond_type)?,
+            "maturity": to_bson(&price.maturity)?,
+            "time": to_bson(&price.time)?,
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+        collection.update_one(filter, doc! { "$set": doc }, options)?;
+    }
+
+    // Bonds are not streamed, so their cached current prices would never change.
+    for (bond_type, maturity) in &bonds {
+        for symbol in get_symbols_for_bond(bond_type, maturity)? {
+            PriceCache::invalidate(&symbol);
+        }
+    }
+
+    Ok(())
+}
+
+/// # List bond prices
+///
+/// Lists all imported bond prices
+#[openapi]
+#[get("/tesouro/prices?<options..>")]
+pub fn get_bond_prices(
+    options: Option<Form<ListingOptions>>,
+) -> WalletResult<Rest<Json<Vec<BondPrice>>>> {
+    api_get::<BondPrice>(None, options)
+}
+
+/// # Get a Tesouro Direto position
+///
+/// Get position for a specific Tesouro Direto bond
+#[openapi]
+#[get("/tesouro/position/<symbol>")]
+pub fn get_tesouro_position_by_symbol(symbol: String) -> WalletResult<Json<Position>> {
+    Position::calculate_for_symbol(&symbol, None, None).map(Json)
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use chrono::TimeZone;
+    use mongodb::bson::{from_bson, Bson};
+    use rusty_fork::rusty_fork_test;
+
+    use super::*;
+    use crate::historical::{refresh_historical_for_symbol, PriceSource};
+    use crate::scheduling::LockMap;
+    use crate::walletdb::insert_one;
+
+    const SYMBOL: &str = "TESOURO-IPCA-2035";
+
+    fn purchase(quantity: f64) -> Event {
+        let detail = doc! {
+            "bondType": "ipca",
+            "maturity": "2035-05-15T00:00:00Z",
+            "price": 1807.25,
+            "quantity": quantity,
+            "type": "purchase",
+            "broker": Bson::Null,
+        };
+
+        Event {
+            id: None,
+            symbol: SYMBOL.to_string(),
+            time: Utc.ymd(2020, 10, 5).and_hms(12, 0, 0),
+            detail: EventDetail::TesouroDiretoOperation(
+                from_bson(Bson::Document(detail)).expect("Failed to parse operation"),
+            ),
+        }
+    }
+
+    #[test]
+    fn bond_fractions() {
+        let bond = match purchase(0.37).detail {
+            EventDetail::TesouroDiretoOperation(bond) => bond,
+            _ => unreachable!(),
+        };
+        assert_eq!(bond.operation.quantity, 37);
+        assert_relative_eq!(bond.operation.price, 18.0725);
+
+        // What is stored is what came in.
+        let doc = match to_bson(&bond).expect("Failed to serialize") {
+            Bson::Document(doc) => doc,
+            _ => unreachable!(),
+        };
+        assert_relative_eq!(doc.get_f64("quantity").unwrap(), 0.37);
+        assert_relative_eq!(doc.get_f64("price").unwrap(), 1807.25);
+
+        let same = from_bson::<TesouroDiretoOperation>(Bson::Document(doc))
+            .expect("Failed to deserialize");
+        assert_eq!(same, bond);
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn bond_prices() {
+            WalletDB::init_client("mongodb://localhost:27017/");
+
+            let db = WalletDB::get_connection();
+            let prices = db.collection(BondPrice::collection_name());
+            let bond_filter = doc! { "bondType": "ipca", "maturity": "2035-05-15T00:00:00Z" };
+
+            let price = |unit_price: f64| BondPrice {
+                id: None,
+                bond_type: BondKind::IPCA,
+                maturity: Utc.ymd(2035, 5, 15).and_hms(0, 0, 0),
+                time: Utc.ymd(2020, 10, 6).and_hms(0, 0, 0),
+                unit_price,
+            };
+
+            // Importing the same day again replaces the price.
+            import_bond_prices(Json(vec![price(1811.09)])).expect("Failed to import");
+            import_bond_prices(Json(vec![price(1812.50)])).expect("Failed to import");
+            assert_eq!(prices.count_documents(bond_filter, None).unwrap(), 1);
+
+            insert_one(purchase(0.5)).expect("Failed to insert event");
+
+            let bond = match PriceSource::for_symbol(SYMBOL).expect("Failed to look up bond") {
+                PriceSource::Bond(bond) => bond,
+                PriceSource::Yahoo => panic!("{} should be priced as a bond", SYMBOL),
+            };
+            let unit_price = BondPrice::get_for_day_with_fallback(&bond, Utc.ymd(2020, 10, 8))
+                .expect("Failed to get price")
+                .unit_price;
+            assert_relative_eq!(unit_price, 1812.50);
+
+            // Bonds are not sent to Yahoo.
+            assert!(refresh_historical_for_symbol(SYMBOL.to_string()).is_ok());
+
+            let position =
+                Position::calculate_for_symbol(SYMBOL, None, None).expect("Something went wrong");
+            assert_eq!(position.quantity, 50);
+            assert_relative_eq!(position.cost_basis, 903.625);
+
+            // Wait for create_snapshots to finish.
+            drop(LockMap::lock(Position::collection_name(), SYMBOL));
+
+            WalletDB::drop_database();
+        }
+    }
+}
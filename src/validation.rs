@@ -0,0 +1,460 @@
+use chrono::{DateTime, Duration, Utc};
+
+use crate::asset::Asset;
+use crate::broker::Broker;
+use crate::config::WalletConfig;
+use crate::error::{BackendError, WalletResult};
+use crate::event::{get_events_for_symbol, Event, EventDetail};
+use crate::fii::FIIOperation;
+use crate::operation::{BaseOperation, OperationKind};
+use crate::portfolio::Portfolio;
+use crate::position::{classify_day_trades, Position};
+use crate::stock::{Merger, SpinOff, StockBonus, StockOperation, StockSplit, TickerChange};
+use crate::tesouro::TesouroDiretoOperation;
+use crate::walletdb::{get_one, Queryable};
+
+/// Checks an event before it is stored. When updating, `oid` is the event being
+/// replaced, which must not count towards the holding the event is checked against.
+pub fn validate_event(event: &Event, oid: Option<&str>) -> WalletResult<()> {
+    check_values(event, Utc::now())?;
+    check_registered(event)?;
+    check_references(event)?;
+    check_holding(event, oid)
+}
+
+fn operation(event: &Event) -> Option<&BaseOperation> {
+    match &event.detail {
+        EventDetail::StockOperation(StockOperation { operation, .. })
+        | EventDetail::FIIOperation(FIIOperation { operation, .. })
+        | EventDetail::TesouroDiretoOperation(TesouroDiretoOperation { operation, .. }) => {
+            Some(operation)
+        }
+        _ => None,
+    }
+}
+
+fn check_positive(field: &str, value: f64) -> WalletResult<()> {
+    if value > 0.0 {
+        Ok(())
+    } else {
+        Err(BackendError::validation(
+            field,
+            &format!("Must be positive, got {}", value),
+        ))
+    }
+}
+
+fn check_values(event: &Event, now: DateTime<Utc>) -> WalletResult<()> {
+    if event.time > now {
+        return Err(BackendError::validation(
+            "time",
+            "Events cannot be dated in the future",
+        ));
+    }
+
+    if let Some(operation) = operation(event) {
+        check_positive("detail.price", operation.price)?;
+        check_positive("detail.quantity", operation.quantity as f64)?;
+    }
+
+    match &event.detail {
+        EventDetail::StockSplit(StockSplit { factor, .. })
+        | EventDetail::Bonus(StockBonus { factor, .. })
+        | EventDetail::Merger(Merger { factor, .. })
+        | EventDetail::SpinOff(SpinOff { factor, .. }) => check_positive("detail.factor", *factor),
+        EventDetail::LendingStart(lending) | EventDetail::LendingEnd(lending) => {
+            check_positive("detail.quantity", lending.quantity as f64)
+        }
+        EventDetail::CustodyTransfer(transfer) => {
+            check_positive("detail.quantity", transfer.quantity as f64)
+        }
+        EventDetail::Dividend(income)
+        | EventDetail::JCP(income)
+        | EventDetail::FIIIncome(income)
+        | EventDetail::LendingIncome(income) => {
+            check_positive("detail.grossAmount", income.gross_amount)?;
+            if income.withholding >= 0.0 {
+                Ok(())
+            } else {
+                Err(BackendError::validation(
+                    "detail.withholding",
+                    &format!("Cannot be negative, got {}", income.withholding),
+                ))
+            }
+        }
+        _ => Ok(()),
+    }?;
+
+    // Spin-offs move part of the cost basis, but never more than all of it.
+    if let EventDetail::SpinOff(SpinOff {
+        cost_percentage, ..
+    }) = &event.detail
+    {
+        if !(*cost_percentage > 0.0 && *cost_percentage <= 100.0) {
+            return Err(BackendError::validation(
+                "detail.costPercentage",
+                &format!("Must be above 0 and up to 100, got {}", cost_percentage),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Symbols, including the ones corporate actions turn holdings into, must be in
+/// the asset registry.
+fn check_registered(event: &Event) -> WalletResult<()> {
+    Asset::check_registered("symbol", &event.symbol)?;
+
+    match &event.detail {
+        EventDetail::TickerChange(TickerChange { new_symbol })
+        | EventDetail::Merger(Merger { new_symbol, .. })
+        | EventDetail::SpinOff(SpinOff { new_symbol, .. }) => {
+            Asset::check_registered("detail.newSymbol", new_symbol)
+        }
+        _ => Ok(()),
+    }
+}
+
+fn exists<T: Queryable>(oid: &str) -> WalletResult<bool> {
+    match get_one::<T>(oid.to_string()) {
+        Ok(_) => Ok(true),
+        Err(BackendError::NotFound) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn check_references(event: &Event) -> WalletResult<()> {
+    let (brokers, portfolios) = match &event.detail {
+        EventDetail::StockOperation(StockOperation { operation, .. })
+        | EventDetail::FIIOperation(FIIOperation { operation, .. })
+        | EventDetail::TesouroDiretoOperation(TesouroDiretoOperation { operation, .. }) => (
+            vec![("detail.broker", operation.broker.clone())],
+            &operation.portfolios,
+        ),
+        EventDetail::Dividend(income)
+        | EventDetail::JCP(income)
+        | EventDetail::FIIIncome(income)
+        | EventDetail::LendingIncome(income) => (
+            vec![("detail.broker", income.broker.clone())],
+            &income.portfolios,
+        ),
+        EventDetail::LendingStart(lending) | EventDetail::LendingEnd(lending) => (
+            vec![("detail.broker", lending.broker.clone())],
+            &lending.portfolios,
+        ),
+        EventDetail::CustodyTransfer(transfer) => (
+            vec![
+                ("detail.fromBroker", Some(transfer.from_broker.clone())),
+                ("detail.toBroker", Some(transfer.to_broker.clone())),
+            ],
+            &transfer.portfolios,
+        ),
+        _ => return Ok(()),
+    };
+
+    for (field, broker) in brokers {
+        if let Some(broker) = broker {
+            if !exists::<Broker>(&broker)? {
+                return Err(BackendError::validation(
+                    field,
+                    &format!("Unknown broker {}", broker),
+                ));
+            }
+        }
+    }
+
+    for portfolio in portfolios {
+        if !exists::<Portfolio>(portfolio)? {
+            return Err(BackendError::validation(
+                "detail.portfolios",
+                &format!("Unknown portfolio {}", portfolio),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Unless short selling is allowed, no event may leave less than what is lent out
+/// held, at its time or any time after it. Editing an event may move it, so the
+/// holding is replayed from whichever of the old and new times comes first.
+/// Custody transfers leave the global holding alone, so they are checked against
+/// what the broker they come from holds.
+fn check_holding(event: &Event, oid: Option<&str>) -> WalletResult<()> {
+    if WalletConfig::allow_short_selling() {
+        return Ok(());
+    }
+
+    let replaced = match oid {
+        Some(oid) => match get_one::<Event>(oid.to_string()) {
+            Ok(replaced) => Some(replaced),
+            Err(BackendError::NotFound) => None,
+            Err(e) => return Err(e),
+        },
+        None => None,
+    };
+
+    let start = replaced
+        .iter()
+        .map(|replaced| replaced.time)
+        .fold(event.time, DateTime::min);
+    let before = start - Duration::seconds(1);
+
+    replay_holding(event, oid, None, before)?;
+    if let EventDetail::CustodyTransfer(transfer) = &event.detail {
+        replay_holding(event, oid, Some(transfer.from_broker.clone()), before)?;
+    }
+
+    Ok(())
+}
+
+/// Replays the holding, globally or at a broker, from right before the given
+/// time on, with the event in place of the one it replaces.
+fn replay_holding(
+    event: &Event,
+    oid: Option<&str>,
+    broker: Option<String>,
+    before: DateTime<Utc>,
+) -> WalletResult<()> {
+    let mut events =
+        get_events_for_symbol(&event.symbol, None, broker.clone(), before, Utc::now())?
+            .into_iter()
+            .filter(|other| oid.map_or(true, |oid| other.id.as_deref() != Some(oid)))
+            .collect::<Vec<Event>>();
+    let index = events
+        .iter()
+        .position(|other| other.time > event.time)
+        .unwrap_or(events.len());
+    events.insert(index, event.clone());
+
+    let mut position = Position::calculate_at_broker(&event.symbol, broker.clone(), before)?;
+    let day_trades = classify_day_trades(&events);
+    for (other, day_trade_quantity) in events.iter().zip(day_trades) {
+        let available = position.quantity - position.lent_quantity;
+        position.apply(other, day_trade_quantity)?;
+
+        if position.quantity - position.lent_quantity < 0 {
+            let date = other.time.date().naive_utc();
+            let message = match (&other.detail, operation(other)) {
+                (_, Some(operation)) if operation.kind == OperationKind::Sale => format!(
+                    "Cannot sell {} {}, only {} held on {}",
+                    operation.quantity,
+                    event.symbol,
+                    available.max(0),
+                    date
+                ),
+                (EventDetail::CustodyTransfer(transfer), _) => format!(
+                    "Cannot transfer {} {}, only {} held at {} on {}",
+                    transfer.quantity,
+                    event.symbol,
+                    available.max(0),
+                    transfer.from_broker,
+                    date
+                ),
+                _ => format!(
+                    "Would leave fewer {} held than lent out on {}",
+                    event.symbol, date
+                ),
+            };
+            return Err(BackendError::validation("detail.quantity", &message));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use rusty_fork::rusty_fork_test;
+
+    use super::*;
+    use crate::income::Income;
+    use crate::operation::{AssetKind, Fees, LotMatching};
+    use crate::stock::{CustodyTransfer, StockSplitKind};
+    use crate::walletdb::{insert_one, WalletDB};
+
+    fn operation(day: u32, kind: OperationKind, quantity: i64) -> Event {
+        Event {
+            id: None,
+            symbol: "HOLD3".to_string(),
+            time: Utc.ymd(2020, 1, day).and_hms(12, 0, 0),
+            detail: EventDetail::StockOperation(StockOperation {
+                asset_kind: AssetKind::Stock,
+                operation: BaseOperation {
+                    price: 10.0,
+                    quantity,
+                    fees: Fees::default(),
+                    kind,
+                    broker: None,
+                    portfolios: vec![],
+                    lot_matching: LotMatching::default(),
+                },
+            }),
+        }
+    }
+
+    fn assert_invalid(result: WalletResult<()>, expected: &str) {
+        match result {
+            Err(BackendError::Validation { field, .. }) => assert_eq!(field, expected),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn event_values() {
+        let now = Utc.ymd(2020, 7, 1).and_hms(0, 0, 0);
+        let sale = Event {
+            id: None,
+            symbol: "PETR4".to_string(),
+            time: Utc.ymd(2020, 6, 1).and_hms(0, 0, 0),
+            detail: EventDetail::StockOperation(StockOperation {
+                asset_kind: AssetKind::Stock,
+                operation: BaseOperation {
+                    price: 20.0,
+                    quantity: 100,
+                    fees: Fees::default(),
+                    kind: OperationKind::Sale,
+                    broker: None,
+                    portfolios: vec![],
+                    lot_matching: LotMatching::default(),
+                },
+            }),
+        };
+        assert!(check_values(&sale, now).is_ok());
+
+        let mut future = sale.clone();
+        future.time = Utc.ymd(2020, 7, 2).and_hms(0, 0, 0);
+        assert_invalid(check_values(&future, now), "time");
+
+        let mut no_price = sale.clone();
+        if let EventDetail::StockOperation(operation) = &mut no_price.detail {
+            operation.operation.price = 0.0;
+        }
+        assert_invalid(check_values(&no_price, now), "detail.price");
+
+        let mut split = sale.clone();
+        split.detail = EventDetail::StockSplit(StockSplit {
+            split_kind: StockSplitKind::Split,
+            factor: -2.0,
+            cash_in: None,
+        });
+        assert_invalid(check_values(&split, now), "detail.factor");
+
+        let spin_off = |cost_percentage: f64| {
+            let mut spin_off = sale.clone();
+            spin_off.detail = EventDetail::SpinOff(SpinOff {
+                new_symbol: "NEWS3".to_string(),
+                factor: 1.0,
+                cost_percentage,
+                cash_in: None,
+            });
+            check_values(&spin_off, now)
+        };
+        assert!(spin_off(100.0).is_ok());
+        assert_invalid(spin_off(0.0), "detail.costPercentage");
+        assert_invalid(spin_off(120.0), "detail.costPercentage");
+
+        let dividend = |gross_amount: f64, withholding: f64| {
+            let mut dividend = sale.clone();
+            dividend.detail = EventDetail::Dividend(Income {
+                gross_amount,
+                withholding,
+                payment_date: now,
+                broker: None,
+                portfolios: vec![],
+            });
+            check_values(&dividend, now)
+        };
+        assert!(dividend(100.0, 0.0).is_ok());
+        assert_invalid(dividend(0.0, 0.0), "detail.grossAmount");
+        assert_invalid(dividend(100.0, -15.0), "detail.withholding");
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn holding() {
+            WalletDB::init_client("mongodb://localhost:27017/");
+
+            let purchase = insert_one(operation(6, OperationKind::Purchase, 100))
+                .expect("Failed to insert event");
+            let sale = insert_one(operation(10, OperationKind::Sale, 80))
+                .expect("Failed to insert event");
+            let (purchase_oid, sale_oid) = (purchase.id.clone().unwrap(), sale.id.clone().unwrap());
+
+            assert!(check_holding(&operation(8, OperationKind::Sale, 20), None).is_ok());
+            assert_invalid(
+                check_holding(&operation(5, OperationKind::Sale, 10), None),
+                "detail.quantity",
+            );
+
+            // Selling 30 before the 10th leaves too little for the sale on it.
+            assert_invalid(
+                check_holding(&operation(8, OperationKind::Sale, 30), None),
+                "detail.quantity",
+            );
+
+            // The sale being edited does not count against itself.
+            assert!(check_holding(&operation(10, OperationKind::Sale, 100), Some(&sale_oid)).is_ok());
+            assert_invalid(
+                check_holding(&operation(10, OperationKind::Sale, 120), Some(&sale_oid)),
+                "detail.quantity",
+            );
+
+            // Neither may a purchase shrink or move past the sales it pays for.
+            assert!(
+                check_holding(&operation(7, OperationKind::Purchase, 80), Some(&purchase_oid))
+                    .is_ok()
+            );
+            assert_invalid(
+                check_holding(&operation(6, OperationKind::Purchase, 50), Some(&purchase_oid)),
+                "detail.quantity",
+            );
+            assert_invalid(
+                check_holding(&operation(12, OperationKind::Purchase, 100), Some(&purchase_oid)),
+                "detail.quantity",
+            );
+
+            WalletDB::drop_database();
+        }
+
+        #[test]
+        fn transfer_holding() {
+            WalletDB::init_client("mongodb://localhost:27017/");
+
+            let at_broker = |mut event: Event| {
+                if let EventDetail::StockOperation(operation) = &mut event.detail {
+                    operation.operation.broker = Some("A".to_string());
+                }
+                event
+            };
+            let transfer = |day: u32, quantity: i64| Event {
+                id: None,
+                symbol: "HOLD3".to_string(),
+                time: Utc.ymd(2020, 1, day).and_hms(12, 0, 0),
+                detail: EventDetail::CustodyTransfer(CustodyTransfer {
+                    quantity,
+                    from_broker: "A".to_string(),
+                    to_broker: "B".to_string(),
+                    portfolios: vec![],
+                }),
+            };
+
+            insert_one(at_broker(operation(6, OperationKind::Purchase, 100)))
+                .expect("Failed to insert event");
+
+            // Transfers leave the global holding alone, but not the broker's.
+            assert!(check_holding(&transfer(8, 100), None).is_ok());
+            assert_invalid(check_holding(&transfer(8, 150), None), "detail.quantity");
+
+            // Nor may they leave too little for later sales at the broker.
+            insert_one(at_broker(operation(10, OperationKind::Sale, 50)))
+                .expect("Failed to insert event");
+            assert!(check_holding(&transfer(8, 50), None).is_ok());
+            assert_invalid(check_holding(&transfer(8, 80), None), "detail.quantity");
+
+            WalletDB::drop_database();
+        }
+    }
+}
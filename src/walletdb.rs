@@ -10,6 +10,7 @@ use std::cell::RefCell;
 use std::sync::Mutex;
 
 use crate::error::{BackendError, WalletResult};
+use crate::rest::ListingOptions;
 
 lazy_static! {
     static ref WALLET_CLIENT: Mutex<RefCell<Option<Client>>> = Mutex::new(RefCell::new(None));
@@ -53,6 +54,14 @@ impl WalletDB {
             .unwrap()
             .database(&NAME)
     }
+
+    /// Each test process has a database of its own, which it drops when done.
+    #[cfg(test)]
+    pub fn drop_database() {
+        if let Err(e) = WalletDB::get_connection().drop(None) {
+            println!("Failed to drop test db {}", format!("{:?}", e));
+        }
+    }
 }
 
 impl Fairing for WalletDB {
@@ -73,6 +82,17 @@ impl Fairing for WalletDB {
 pub trait Queryable: Serialize + DeserializeOwned + std::fmt::Debug {
     fn collection_name() -> &'static str;
 
+    /// Fields searched for the free text filter of listings.
+    fn search_fields() -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Builds the mongodb filter of listings. Kinds of objects with filters of
+    /// their own, like events, add those here.
+    fn listing_filter(options: &ListingOptions) -> WalletResult<Document> {
+        options.filter(Self::search_fields())
+    }
+
     fn from_docs(cursor: Cursor) -> WalletResult<Vec<Self>> {
         cursor
             .map(|result| result.map(Self::from_doc)?)
@@ -129,14 +149,14 @@ where
     T::from_docs(cursor)
 }
 
-pub fn get_count<T>() -> WalletResult<i64>
+pub fn get_count<T>(filter: Document) -> WalletResult<i64>
 where
     T: Queryable,
 {
     let wallet = WalletDB::get_connection();
     let count = wallet
         .collection(T::collection_name())
-        .count_documents(None, None)?;
+        .count_documents(filter, None)?;
     Ok(count)
 }
 